@@ -0,0 +1,126 @@
+use std::fmt;
+
+use ring::hmac;
+
+use crate::packet::{CHECKSUM_OFFSET, CHECKSUM_PLACEHOLDER, calculate_checksum};
+
+/// An algorithm filling in the 16-bit checksum field of the packet header.
+///
+/// Every algorithm is calculated over the whole encoded packet, with
+/// `0xCCCC` replacing the checksum field, so a packet can be checked with its
+/// checksum filled in.
+pub trait Checksum: fmt::Debug + Send + Sync {
+    /// Calculates the checksum of `bytes`, an encoded packet.
+    fn calculate(&self, bytes: &[u8]) -> u16;
+}
+
+/// The 'Internet Checksum' defined in RFC 1071. The default algorithm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rfc1071;
+
+impl Checksum for Rfc1071 {
+    fn calculate(&self, bytes: &[u8]) -> u16 {
+        calculate_checksum(bytes)
+    }
+}
+
+/// CRC-32C (Castagnoli), truncated to its low 16 bits to fit the checksum
+/// field. Unlike RFC 1071, it catches reordered words and burst errors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32c;
+
+impl Checksum for Crc32c {
+    fn calculate(&self, bytes: &[u8]) -> u16 {
+        let crc = without_checksum(bytes)
+            .iter()
+            .fold(0, |crc, part| crc32c::crc32c_append(crc, part));
+        crc as u16
+    }
+}
+
+/// HMAC-SHA256 keyed with a shared secret, truncated to its first 16 bits to
+/// fit the checksum field. Only senders that know the key can give a packet a
+/// valid checksum, though at 16 bits one can still be guessed.
+#[derive(Debug, Clone)]
+pub struct HmacSha256 {
+    key: hmac::Key,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        HmacSha256 {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+        }
+    }
+}
+
+impl Checksum for HmacSha256 {
+    fn calculate(&self, bytes: &[u8]) -> u16 {
+        let mut context = hmac::Context::with_key(&self.key);
+        for part in without_checksum(bytes) {
+            context.update(part);
+        }
+
+        let tag = context.sign();
+        u16::from_be_bytes([tag.as_ref()[0], tag.as_ref()[1]])
+    }
+}
+
+/// Splits `bytes` around the checksum field, with the placeholder in its place.
+fn without_checksum(bytes: &[u8]) -> [&[u8]; 3] {
+    const PLACEHOLDER: [u8; 2] = CHECKSUM_PLACEHOLDER.to_be_bytes();
+
+    let start = CHECKSUM_OFFSET.min(bytes.len());
+    let end = (CHECKSUM_OFFSET + PLACEHOLDER.len()).min(bytes.len());
+
+    [&bytes[..start], &PLACEHOLDER[..end - start], &bytes[end..]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+
+    /// A packet with "123456789", the data checksum check values are usually
+    /// given for, and the checksum field left empty.
+    fn packet() -> Packet {
+        let mut packet = Packet::new(0, b"123456789".to_vec()).unwrap();
+        packet.header.checksum = 0;
+        packet
+    }
+
+    /// Checks the checksum is the same whatever the checksum field holds, so a
+    /// packet with it filled in still verifies.
+    fn assert_ignores_checksum_field(checksum: &dyn Checksum, expected: u16) {
+        let mut packet = packet();
+        assert_eq!(checksum.calculate(&packet.encode()), expected);
+
+        packet.header.checksum = 0xFFFF;
+        assert_eq!(checksum.calculate(&packet.encode()), expected);
+
+        packet.header.checksum = expected;
+        assert!(packet.check_checksum_with(checksum).is_ok());
+    }
+
+    #[test]
+    fn crc32c_known_answer() {
+        assert_eq!(crc32c::crc32c(b"123456789"), 0xE306_9283);
+        assert_ignores_checksum_field(&Crc32c, 0x910E);
+    }
+
+    #[test]
+    fn hmac_sha256_known_answer() {
+        assert_ignores_checksum_field(&HmacSha256::new(b"key"), 0x7BB0);
+        assert_ne!(HmacSha256::new(b"other key").calculate(&packet().encode()), 0x7BB0);
+    }
+
+    #[test]
+    fn rfc1071_matches_calculate_checksum() {
+        assert_ignores_checksum_field(&Rfc1071, 0x5D54);
+
+        for data in [&b""[..], b"1", b"123456789", &[0xFF; 64]] {
+            let bytes = Packet::new(0, data.to_vec()).unwrap().encode();
+            assert_eq!(Rfc1071.calculate(&bytes), calculate_checksum(&bytes));
+        }
+    }
+}
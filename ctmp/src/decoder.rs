@@ -0,0 +1,823 @@
+use std::io::{Error, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::checksum::{Checksum, Rfc1071};
+use crate::error::CtmpError;
+use crate::packet::{ChecksumPolicy, Header, MAGIC, MAX_PACKET_LEN, Packet};
+use crate::trailer::HmacTrailer;
+
+/// Default number of bytes requested from the reader on each `Decoder::read_from`.
+pub const DEFAULT_READ_LEN: usize = 16 * 1024;
+
+/// Default longest packet data accepted as the first packet found while
+/// resynchronizing.
+pub const DEFAULT_RESYNC_MAX_LENGTH: u16 = 4096;
+
+/// How the decoder recovers when the stream no longer starts with a packet
+/// header, i.e. the magic byte is wrong or the length is over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResyncPolicy {
+    /// Discard everything buffered and start again with the next read.
+    #[default]
+    Off,
+    /// Skip to the next magic byte followed by a length within the limit.
+    Magic,
+    /// Like `Magic`, but the packet must also have a valid checksum.
+    Checksum,
+}
+
+impl FromStr for ResyncPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ResyncPolicy, Error> {
+        match s {
+            "off" => Ok(ResyncPolicy::Off),
+            "magic" => Ok(ResyncPolicy::Magic),
+            "checksum" => Ok(ResyncPolicy::Checksum),
+            _ => Err(Error::other(format!("Unknown resync policy: {}", s))),
+        }
+    }
+}
+
+/// What the decoder does with a packet whose checksum is required but wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BadChecksumPolicy {
+    /// Discard the packet and return `CtmpError::BadChecksum`.
+    #[default]
+    Reject,
+    /// Replace the checksum field with the calculated checksum and return the
+    /// packet. For producers known to calculate the checksum wrong.
+    Repair,
+}
+
+impl FromStr for BadChecksumPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BadChecksumPolicy, Error> {
+        match s {
+            "reject" => Ok(BadChecksumPolicy::Reject),
+            "repair" => Ok(BadChecksumPolicy::Repair),
+            _ => Err(Error::other(format!("Unknown bad checksum policy: {}", s))),
+        }
+    }
+}
+
+/// How strictly packet headers are checked against the spec, beyond what's
+/// needed to frame the packet. See `Header::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderValidation {
+    /// Unknown option bits and the padding are ignored.
+    #[default]
+    Off,
+    /// Nonconforming packets are returned, and `nonconforming` says why.
+    Lenient,
+    /// Nonconforming packets are discarded with an error.
+    Strict,
+}
+
+impl FromStr for HeaderValidation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HeaderValidation, Error> {
+        match s {
+            "off" => Ok(HeaderValidation::Off),
+            "lenient" => Ok(HeaderValidation::Lenient),
+            "strict" => Ok(HeaderValidation::Strict),
+            _ => Err(Error::other(format!("Unknown header validation: {}", s))),
+        }
+    }
+}
+
+/// Splits a byte stream into CTMP packets.
+///
+/// Bytes are buffered between calls, so a read may contain any number of
+/// packets, including a packet split across several reads or several packets
+/// coalesced into one read.
+#[derive(Debug)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    read_len: usize,
+    checksum_policy: ChecksumPolicy,
+    /// Algorithm the checksums of packets are calculated with.
+    checksum: Arc<dyn Checksum>,
+    /// If set, 'sensitive' packets must carry an HMAC trailer, checked instead
+    /// of their checksum.
+    trailer: Option<Arc<HmacTrailer>>,
+    bad_checksum_policy: BadChecksumPolicy,
+    header_validation: HeaderValidation,
+    /// Longest packet data accepted, from the header's length field.
+    max_length: u16,
+    resync_policy: ResyncPolicy,
+    /// Longest packet data accepted as the first packet found while
+    /// resynchronizing. A stray magic byte is often followed by a large
+    /// length, so this is kept well below `max_length`.
+    resync_max_length: u16,
+    /// Number of bytes skipped so far while resynchronizing. Zero unless
+    /// resynchronizing.
+    skipped: usize,
+    /// Bytes discarded by the last failed decode, at most `MAX_PACKET_LEN`.
+    rejected: Vec<u8>,
+    /// Original checksum field of the last decoded packet, if it was repaired.
+    repaired: Option<u16>,
+    /// Why the header of the last decoded packet doesn't follow the spec, if
+    /// it doesn't and `HeaderValidation::Lenient` let it through.
+    nonconforming: Option<CtmpError>,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            buffer: Vec::new(),
+            read_len: DEFAULT_READ_LEN,
+            checksum_policy: ChecksumPolicy::default(),
+            checksum: Arc::new(Rfc1071),
+            trailer: None,
+            bad_checksum_policy: BadChecksumPolicy::default(),
+            header_validation: HeaderValidation::default(),
+            max_length: u16::MAX,
+            resync_max_length: DEFAULT_RESYNC_MAX_LENGTH,
+            resync_policy: ResyncPolicy::default(),
+            skipped: 0,
+            rejected: Vec::new(),
+            repaired: None,
+            nonconforming: None,
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Sets the number of bytes requested from the reader on each read.
+    pub fn with_read_len(mut self, read_len: usize) -> Decoder {
+        self.read_len = read_len;
+        self
+    }
+
+    /// Sets which packets must carry a valid checksum.
+    pub fn with_checksum_policy(mut self, checksum_policy: ChecksumPolicy) -> Decoder {
+        self.checksum_policy = checksum_policy;
+        self
+    }
+
+    /// Sets the algorithm checksums are calculated with.
+    pub fn with_checksum(mut self, checksum: Arc<dyn Checksum>) -> Decoder {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Sets the key 'sensitive' packets' HMAC trailers are checked with. If
+    /// set, their checksums aren't checked.
+    pub fn with_trailer(mut self, trailer: Option<Arc<HmacTrailer>>) -> Decoder {
+        self.trailer = trailer;
+        self
+    }
+
+    /// Sets what happens to a packet whose checksum is required but wrong.
+    pub fn with_bad_checksum_policy(mut self, bad_checksum_policy: BadChecksumPolicy) -> Decoder {
+        self.bad_checksum_policy = bad_checksum_policy;
+        self
+    }
+
+    /// Sets how strictly packet headers are checked against the spec.
+    pub fn with_header_validation(mut self, header_validation: HeaderValidation) -> Decoder {
+        self.header_validation = header_validation;
+        self
+    }
+
+    /// Sets the longest packet data accepted. A header with a longer length is
+    /// treated like a bad magic byte, as the stream is probably corrupted.
+    pub fn with_max_length(mut self, max_length: u16) -> Decoder {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Sets how the decoder recovers from a corrupted stream.
+    pub fn with_resync_policy(mut self, resync_policy: ResyncPolicy) -> Decoder {
+        self.resync_policy = resync_policy;
+        self
+    }
+
+    /// Sets the longest packet data accepted as the first packet found while
+    /// resynchronizing. Longer candidates are skipped.
+    pub fn with_resync_max_length(mut self, resync_max_length: u16) -> Decoder {
+        self.resync_max_length = resync_max_length;
+        self
+    }
+
+    pub fn set_read_len(&mut self, read_len: usize) {
+        self.read_len = read_len;
+    }
+
+    pub fn set_checksum_policy(&mut self, checksum_policy: ChecksumPolicy) {
+        self.checksum_policy = checksum_policy;
+    }
+
+    pub fn set_checksum(&mut self, checksum: Arc<dyn Checksum>) {
+        self.checksum = checksum;
+    }
+
+    pub fn set_trailer(&mut self, trailer: Option<Arc<HmacTrailer>>) {
+        self.trailer = trailer;
+    }
+
+    pub fn set_bad_checksum_policy(&mut self, bad_checksum_policy: BadChecksumPolicy) {
+        self.bad_checksum_policy = bad_checksum_policy;
+    }
+
+    pub fn set_header_validation(&mut self, header_validation: HeaderValidation) {
+        self.header_validation = header_validation;
+    }
+
+    pub fn set_max_length(&mut self, max_length: u16) {
+        self.max_length = max_length;
+    }
+
+    pub fn set_resync_policy(&mut self, resync_policy: ResyncPolicy) {
+        self.resync_policy = resync_policy;
+    }
+
+    pub fn set_resync_max_length(&mut self, resync_max_length: u16) {
+        self.resync_max_length = resync_max_length;
+    }
+
+    /// Appends `bytes` to the end of the buffered stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Performs a single read from `reader` into the buffered stream.
+    ///
+    /// Returns the number of bytes read, or `CtmpError::PeerClosed` if the
+    /// reader has no more data (i.e., EOF).
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> Result<usize, CtmpError> {
+        let buffered = self.buffer.len();
+        self.buffer.resize(buffered + self.read_len, 0);
+
+        let result = reader.read(&mut self.buffer[buffered..]);
+        let bytes_read = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(buffered + bytes_read);
+
+        match result? {
+            0 => Err(CtmpError::PeerClosed),
+            bytes_read => Ok(bytes_read),
+        }
+    }
+
+    /// Takes the next complete packet off the front of the buffered stream.
+    ///
+    /// Returns `Ok(None)` if more data is needed to complete the packet.
+    ///
+    /// Returns error if the next packet is invalid:
+    ///  - Packet magic byte is incorrect, or the length is over the limit. With
+    ///    resync off, the buffered stream is discarded, as the start of the
+    ///    next packet is unknown. Otherwise bytes are skipped until the next
+    ///    plausible header, and `CtmpError::Resynced` reports how many once
+    ///    it's found.
+    ///  - Packet requires a checksum and the checksum field doesn't match the
+    ///    calculated checksum. Only the bad packet is discarded. With
+    ///    `BadChecksumPolicy::Repair`, the packet is returned with the
+    ///    calculated checksum instead, and `repaired` returns the original.
+    ///  - With a trailer key set, the packet is 'sensitive' and its HMAC
+    ///    trailer is missing or wrong. Only the bad packet is discarded.
+    ///  - With `HeaderValidation::Strict`, the packet has unknown option bits
+    ///    or non-zero padding. Only the bad packet is discarded.
+    pub fn decode(&mut self) -> Result<Option<Packet>, CtmpError> {
+        self.repaired = None;
+        self.nonconforming = None;
+
+        // Bytes skipped while resynchronizing add up until the next packet is found.
+        if self.skipped == 0 {
+            self.rejected.clear();
+        }
+
+        // If the packet data is less than the header length, wait for more.
+        let Some(header) = Header::parse(&self.buffer) else {
+            return Ok(None);
+        };
+
+        if self.resync_policy != ResyncPolicy::Off {
+            match self.is_plausible(&self.buffer, self.skipped > 0) {
+                None | Some(false) => return self.resync(),
+                Some(true) if self.skipped > 0 => {
+                    let skipped = std::mem::take(&mut self.skipped);
+                    return Err(CtmpError::Resynced { skipped });
+                }
+                Some(true) => {}
+            }
+        } else if header.magic != MAGIC {
+            self.discard(self.buffer.len());
+            return Err(CtmpError::BadMagic(header.magic));
+        } else if header.length > self.max_length {
+            self.discard(self.buffer.len());
+            return Err(CtmpError::Oversize(header.length as usize));
+        }
+
+        // If the rest of the packet hasn't arrived yet, wait for more.
+        if self.buffer.len() < header.packet_len() {
+            return Ok(None);
+        }
+
+        let mut result = Packet::parse_with(&self.buffer[..header.packet_len()], ChecksumPolicy::Off);
+
+        if let Ok(packet) = &result
+            && packet.header.is_sensitive()
+            && let Some(trailer) = &self.trailer
+        {
+            result = trailer.verify(packet).and(result);
+        } else if let Ok(packet) = &mut result
+            && self.checksum_policy.requires_checksum(&packet.header)
+            && let Err(e) = packet.check_checksum_with(self.checksum.as_ref())
+        {
+            match (e, self.bad_checksum_policy) {
+                (CtmpError::BadChecksum { expected, actual }, BadChecksumPolicy::Repair) => {
+                    packet.header.checksum = actual;
+                    self.repaired = Some(expected);
+                }
+                (e, _) => result = Err(e),
+            }
+        }
+
+        if result.is_ok()
+            && let Err(e) = header.validate()
+        {
+            match self.header_validation {
+                HeaderValidation::Off => {}
+                HeaderValidation::Lenient => self.nonconforming = Some(e),
+                HeaderValidation::Strict => {
+                    self.repaired = None;
+                    result = Err(e);
+                }
+            }
+        }
+
+        if result.is_ok() {
+            self.buffer.drain(..header.packet_len());
+        } else {
+            self.discard(header.packet_len());
+        }
+
+        result.map(Some)
+    }
+
+    /// Discards whatever is left buffered once the stream has ended, e.g. a
+    /// packet cut off by the peer disconnecting.
+    ///
+    /// Returns `CtmpError::Incomplete` if anything was left, with the bytes,
+    /// and any skipped while resynchronizing, in `rejected`.
+    pub fn finish(&mut self) -> Result<(), CtmpError> {
+        if self.skipped == 0 {
+            self.rejected.clear();
+        }
+
+        if self.buffer.is_empty() && self.skipped == 0 {
+            return Ok(());
+        }
+
+        self.skipped = 0;
+        self.discard(self.buffer.len());
+        Err(CtmpError::Incomplete)
+    }
+
+    /// Checks whether `bytes` start with a plausible packet, or `None` if more
+    /// bytes are needed to tell.
+    ///
+    /// Once a packet boundary is known, any packet with the right magic byte
+    /// and length is plausible; a bad checksum only loses that packet. While
+    /// `resyncing`, the length must be within `resync_max_length`, and with
+    /// `ResyncPolicy::Checksum` the checksum must be valid too, or the HMAC
+    /// trailer of a 'sensitive' packet if a trailer key is set.
+    fn is_plausible(&self, bytes: &[u8], resyncing: bool) -> Option<bool> {
+        let header = Header::parse(bytes)?;
+
+        let max_length = if resyncing {
+            self.max_length.min(self.resync_max_length)
+        } else {
+            self.max_length
+        };
+
+        if header.magic != MAGIC || header.length > max_length {
+            return Some(false);
+        }
+
+        if !resyncing || self.resync_policy != ResyncPolicy::Checksum {
+            return Some(true);
+        }
+
+        if bytes.len() < header.packet_len() {
+            return None;
+        }
+
+        // Checked the same way `decode` checks it.
+        let packet = Packet::parse_with(&bytes[..header.packet_len()], ChecksumPolicy::Off);
+        Some(packet.is_ok_and(|packet| match &self.trailer {
+            Some(trailer) if packet.header.is_sensitive() => trailer.verify(&packet).is_ok(),
+            _ => packet.check_checksum_with(self.checksum.as_ref()).is_ok(),
+        }))
+    }
+
+    /// Skips to the next plausible packet after the front of the buffer.
+    ///
+    /// Returns `CtmpError::Resynced` once one is found, or `Ok(None)` if more
+    /// data is needed to find one.
+    fn resync(&mut self) -> Result<Option<Packet>, CtmpError> {
+        // Candidates that need more data to tell are only waited for if no
+        // later candidate is a complete, plausible packet, so one can't hold
+        // back the packets behind it. The front of the buffer is one if it
+        // was kept waiting by an earlier call.
+        let mut incomplete = (self.skipped > 0 && self.is_plausible(&self.buffer, true).is_none()).then_some(0);
+
+        for position in 1..self.buffer.len() {
+            if self.buffer[position] != MAGIC {
+                continue;
+            }
+
+            match self.is_plausible(&self.buffer[position..], true) {
+                Some(true) => {
+                    self.skip(position);
+                    let skipped = std::mem::take(&mut self.skipped);
+                    return Err(CtmpError::Resynced { skipped });
+                }
+                Some(false) => {}
+                None => {
+                    incomplete.get_or_insert(position);
+                }
+            }
+        }
+
+        // Keep the first incomplete candidate until enough of it has arrived.
+        self.skip(incomplete.unwrap_or(self.buffer.len()));
+        Ok(None)
+    }
+
+    /// Skips the first `len` bytes of the buffered stream while resynchronizing.
+    fn skip(&mut self, len: usize) {
+        self.skipped += len;
+        self.discard(len);
+    }
+
+    /// Removes the first `len` bytes of the buffered stream, keeping them for
+    /// `rejected` up to `MAX_PACKET_LEN` bytes in total.
+    fn discard(&mut self, len: usize) {
+        let room = MAX_PACKET_LEN.saturating_sub(self.rejected.len());
+        self.rejected.extend_from_slice(&self.buffer[..len.min(room)]);
+        self.buffer.drain(..len);
+    }
+
+    /// Key 'sensitive' packets' HMAC trailers are checked with, if any.
+    pub fn trailer(&self) -> Option<&Arc<HmacTrailer>> {
+        self.trailer.as_ref()
+    }
+
+    /// Algorithm the checksums of packets are calculated with.
+    pub fn checksum(&self) -> &Arc<dyn Checksum> {
+        &self.checksum
+    }
+
+    /// Bytes discarded by the last `decode` that returned an error, e.g. the
+    /// packet with a bad checksum, or the bytes skipped to resynchronize.
+    /// Only the first `MAX_PACKET_LEN` bytes are kept.
+    pub fn rejected(&self) -> &[u8] {
+        &self.rejected
+    }
+
+    /// Original checksum field of the packet returned by the last `decode`, if
+    /// it was wrong and `BadChecksumPolicy::Repair` replaced it.
+    pub fn repaired(&self) -> Option<u16> {
+        self.repaired
+    }
+
+    /// Why the header of the packet returned by the last `decode` doesn't
+    /// follow the spec, if it doesn't and `HeaderValidation::Lenient` let it
+    /// through.
+    pub fn nonconforming(&self) -> Option<&CtmpError> {
+        self.nonconforming.as_ref()
+    }
+
+    /// Number of bytes buffered but not yet decoded.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{HEADER_LEN, OPTION_CONTROL, OPTION_SENSITIVE};
+
+    fn packet(options: u8, data: &[u8]) -> Packet {
+        Packet::new(options, data.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn decodes_two_packets_from_one_feed() {
+        let first = packet(0, b"one");
+        let second = packet(OPTION_SENSITIVE, b"two");
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&[first.encode(), second.encode()].concat());
+
+        assert_eq!(decoder.decode().unwrap(), Some(first));
+        assert_eq!(decoder.decode().unwrap(), Some(second));
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn decodes_packet_split_at_every_offset() {
+        let packet = packet(OPTION_SENSITIVE, b"split");
+        let bytes = packet.encode();
+
+        for split in 0..=bytes.len() {
+            let mut decoder = Decoder::new();
+
+            decoder.feed(&bytes[..split]);
+            if split < bytes.len() {
+                assert_eq!(decoder.decode().unwrap(), None, "split at {}", split);
+            }
+
+            decoder.feed(&bytes[split..]);
+            assert_eq!(decoder.decode().unwrap(), Some(packet.clone()), "split at {}", split);
+            assert_eq!(decoder.buffered(), 0);
+        }
+    }
+
+    #[test]
+    fn bad_magic_discards_buffer() {
+        let mut bytes = packet(0, b"bad").encode();
+        bytes[0] = 0x01;
+        bytes.extend_from_slice(&packet(0, b"good").encode());
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::BadMagic(0x01))));
+        assert_eq!(decoder.rejected(), bytes);
+        assert_eq!(decoder.buffered(), 0);
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn bad_checksum_loses_only_that_packet() {
+        let mut bad = packet(OPTION_SENSITIVE, b"bad").encode();
+        bad[4] ^= 0xFF;
+        let good = packet(OPTION_SENSITIVE, b"good");
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&[bad.clone(), good.encode()].concat());
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::BadChecksum { .. })));
+        assert_eq!(decoder.rejected(), bad);
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+    }
+
+    fn resyncing(resync_policy: ResyncPolicy) -> Decoder {
+        Decoder::new().with_resync_policy(resync_policy)
+    }
+
+    /// A header with the magic byte and a bad checksum, followed by its data.
+    fn fake_header() -> Vec<u8> {
+        vec![MAGIC, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, b'z', b'z']
+    }
+
+    #[test]
+    fn magic_resync_skips_to_next_magic_byte() {
+        let packet = packet(0, b"found");
+        let bytes = [b"xx".to_vec(), fake_header(), packet.encode()].concat();
+
+        let mut decoder = resyncing(ResyncPolicy::Magic);
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 2 })));
+        assert_eq!(decoder.rejected(), b"xx");
+        assert_eq!(decoder.decode().unwrap().unwrap().data, b"zz");
+        assert_eq!(decoder.decode().unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn checksum_resync_skips_headers_with_bad_checksums() {
+        let packet = packet(0, b"found");
+        let bytes = [b"xx".to_vec(), fake_header(), packet.encode()].concat();
+
+        let mut decoder = resyncing(ResyncPolicy::Checksum);
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 12 })));
+        assert_eq!(decoder.rejected(), &bytes[..12]);
+        assert_eq!(decoder.decode().unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn checksum_resync_waits_for_candidate_cut_off_by_read() {
+        let bytes = packet(0, b"found").encode();
+
+        let mut decoder = resyncing(ResyncPolicy::Checksum);
+        decoder.feed(b"xx");
+        decoder.feed(&bytes[..HEADER_LEN + 1]);
+
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert_eq!(decoder.buffered(), HEADER_LEN + 1);
+
+        decoder.feed(&bytes[HEADER_LEN + 1..]);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 2 })));
+        assert_eq!(decoder.decode().unwrap().map(|packet| packet.encode()), Some(bytes));
+    }
+
+    #[test]
+    fn checksum_resync_scans_past_incomplete_candidate() {
+        let packet = packet(0, b"found");
+        let stray = [0x01, MAGIC, 0x00, 0x0F, 0xFF];
+        let bytes = [&stray[..], &packet.encode()].concat();
+
+        let mut decoder = resyncing(ResyncPolicy::Checksum);
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 5 })));
+        assert_eq!(decoder.decode().unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn rejected_collects_skipped_bytes_across_reads() {
+        let packet = packet(0, b"found");
+
+        let mut decoder = resyncing(ResyncPolicy::Magic);
+        decoder.feed(b"junkjunk");
+
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert_eq!(decoder.rejected(), b"junkjunk");
+
+        decoder.feed(&[&b"more"[..], &packet.encode()].concat());
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 12 })));
+        assert_eq!(decoder.rejected(), b"junkjunkmore");
+        assert_eq!(decoder.decode().unwrap(), Some(packet));
+        assert!(decoder.rejected().is_empty());
+    }
+
+    #[test]
+    fn finish_rejects_packet_cut_off_by_end_of_stream() {
+        let bytes = packet(0, b"cut off").encode();
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&bytes[..HEADER_LEN + 2]);
+
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert!(matches!(decoder.finish(), Err(CtmpError::Incomplete)));
+        assert_eq!(decoder.rejected(), &bytes[..HEADER_LEN + 2]);
+        assert_eq!(decoder.buffered(), 0);
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn checksum_resync_checks_trailer_of_sensitive_packets() {
+        let trailer = Arc::new(HmacTrailer::new(b"key"));
+        let mut signed = packet(OPTION_SENSITIVE, b"signed");
+        trailer.sign(&mut signed).unwrap();
+        signed.header.checksum = 0;
+
+        let mut decoder = resyncing(ResyncPolicy::Checksum).with_trailer(Some(trailer));
+        decoder.feed(&[&b"xx"[..], &signed.encode()].concat());
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::Resynced { skipped: 2 })));
+        assert_eq!(decoder.decode().unwrap(), Some(signed));
+    }
+
+    #[test]
+    fn repair_replaces_bad_checksum() {
+        let good = packet(OPTION_SENSITIVE, b"repair");
+        let mut bytes = good.encode();
+        bytes[4..6].copy_from_slice(&0x1234u16.to_be_bytes());
+
+        let mut decoder = Decoder::new().with_bad_checksum_policy(BadChecksumPolicy::Repair);
+        decoder.feed(&bytes);
+
+        let repaired = decoder.decode().unwrap().unwrap();
+        assert_eq!(repaired.encode()[4..6], good.header.checksum.to_be_bytes());
+        assert_eq!(repaired, good);
+        assert_eq!(decoder.repaired(), Some(0x1234));
+
+        decoder.feed(&good.encode());
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+        assert_eq!(decoder.repaired(), None);
+    }
+
+    #[test]
+    fn reject_drops_bad_checksum() {
+        let mut bytes = packet(OPTION_SENSITIVE, b"reject").encode();
+        bytes[4..6].copy_from_slice(&0x1234u16.to_be_bytes());
+
+        let mut decoder = Decoder::new().with_bad_checksum_policy(BadChecksumPolicy::Reject);
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::BadChecksum { expected: 0x1234, .. })));
+        assert_eq!(decoder.repaired(), None);
+        assert_eq!(decoder.buffered(), 0);
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    fn with_checksum_field(packet: &Packet, checksum: u16) -> Vec<u8> {
+        let mut bytes = packet.encode();
+        bytes[4..6].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn non_zero_policy_only_checks_filled_in_checksums() {
+        let good = packet(0, b"plain");
+        let mut decoder = Decoder::new().with_checksum_policy(ChecksumPolicy::NonZero);
+
+        decoder.feed(&with_checksum_field(&good, 0));
+        assert_eq!(decoder.decode().unwrap().map(|packet| packet.data), Some(good.data.clone()));
+
+        decoder.feed(&with_checksum_field(&good, 0x1234));
+        assert!(matches!(decoder.decode(), Err(CtmpError::BadChecksum { expected: 0x1234, .. })));
+
+        decoder.feed(&good.encode());
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+    }
+
+    #[test]
+    fn all_policy_checks_packets_that_are_not_sensitive() {
+        let good = packet(0, b"plain");
+        let bad = with_checksum_field(&good, 0x1234);
+
+        let mut decoder = Decoder::new();
+        decoder.feed(&bad);
+        assert!(decoder.decode().unwrap().is_some());
+
+        let mut decoder = Decoder::new().with_checksum_policy(ChecksumPolicy::All);
+        decoder.feed(&bad);
+        assert!(matches!(decoder.decode(), Err(CtmpError::BadChecksum { expected: 0x1234, .. })));
+
+        decoder.feed(&good.encode());
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+    }
+
+    fn with_padding(padding: u16) -> Packet {
+        let mut packet = packet(0, b"padded");
+        packet.header.padding = padding;
+        packet.header.checksum = packet.calculate_checksum();
+        packet
+    }
+
+    #[test]
+    fn lenient_validation_forwards_nonconforming_packets() {
+        let unknown = packet(0x01, b"unknown");
+        let padded = with_padding(0x0001);
+        let good = packet(0, b"good");
+
+        let mut decoder = Decoder::new().with_header_validation(HeaderValidation::Lenient);
+        decoder.feed(&[unknown.encode(), padded.encode(), good.encode()].concat());
+
+        assert_eq!(decoder.decode().unwrap(), Some(unknown));
+        assert!(matches!(decoder.nonconforming(), Some(CtmpError::UnknownOptions(0x01))));
+        assert_eq!(decoder.decode().unwrap(), Some(padded));
+        assert!(matches!(decoder.nonconforming(), Some(CtmpError::NonZeroPadding(0x0001))));
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+        assert!(decoder.nonconforming().is_none());
+    }
+
+    #[test]
+    fn strict_validation_rejects_nonconforming_packets() {
+        let unknown = packet(0x01, b"unknown").encode();
+        let padded = with_padding(0x0001).encode();
+        let good = packet(0, b"good");
+
+        let mut decoder = Decoder::new().with_header_validation(HeaderValidation::Strict);
+        decoder.feed(&[unknown.clone(), padded.clone(), good.encode()].concat());
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::UnknownOptions(0x01))));
+        assert_eq!(decoder.rejected(), unknown);
+        assert!(matches!(decoder.decode(), Err(CtmpError::NonZeroPadding(0x0001))));
+        assert_eq!(decoder.rejected(), padded);
+        assert_eq!(decoder.decode().unwrap(), Some(good));
+    }
+
+    #[test]
+    fn strict_validation_clears_repaired_checksum() {
+        let bytes = with_checksum_field(&packet(OPTION_SENSITIVE | 0x01, b"unknown"), 0x1234);
+
+        let mut decoder = Decoder::new()
+            .with_bad_checksum_policy(BadChecksumPolicy::Repair)
+            .with_header_validation(HeaderValidation::Strict);
+        decoder.feed(&bytes);
+
+        assert!(matches!(decoder.decode(), Err(CtmpError::UnknownOptions(0x01))));
+        assert_eq!(decoder.repaired(), None);
+    }
+
+    #[test]
+    fn control_option_is_known() {
+        let control = packet(OPTION_CONTROL | OPTION_SENSITIVE, b"control");
+
+        let mut decoder = Decoder::new().with_header_validation(HeaderValidation::Strict);
+        decoder.feed(&control.encode());
+
+        assert_eq!(decoder.decode().unwrap(), Some(control));
+        assert!(decoder.nonconforming().is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io;
+
+/// Why a CTMP packet couldn't be read, parsed or built.
+#[derive(Debug)]
+pub enum CtmpError {
+    /// Fewer bytes than the packet needs.
+    Incomplete,
+    /// The first byte of the packet isn't `MAGIC`.
+    BadMagic(u8),
+    /// The checksum field doesn't match the checksum calculated over the packet.
+    BadChecksum { expected: u16, actual: u16 },
+    /// The packet data is longer than allowed: it doesn't fit in the 16-bit
+    /// length field, or the header's length field is over the decoder's limit.
+    Oversize(usize),
+    /// The header's options field has bits set that have no defined meaning.
+    UnknownOptions(u8),
+    /// The header's padding field isn't zero.
+    NonZeroPadding(u16),
+    /// A 'sensitive' packet's HMAC trailer is missing or wasn't signed with
+    /// the expected key.
+    BadTrailer,
+    /// Bytes were skipped to find the start of the next packet after the
+    /// stream was corrupted.
+    Resynced { skipped: usize },
+    /// The peer closed the connection.
+    PeerClosed,
+    /// Reading from the peer failed, or would block.
+    Io(io::Error),
+}
+
+impl CtmpError {
+    /// Checks whether the error only means there's nothing to read right now.
+    pub fn is_would_block(&self) -> bool {
+        matches!(self, CtmpError::Io(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl fmt::Display for CtmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CtmpError::Incomplete => write!(f, "Packet is incomplete"),
+            CtmpError::BadMagic(magic) => write!(f, "Invalid magic byte: {:#04x}", magic),
+            CtmpError::BadChecksum { expected, actual } => {
+                write!(f, "Wrong checksum! Expected: {}, Actual: {}", expected, actual)
+            }
+            CtmpError::Oversize(len) => write!(f, "Packet data too long: {} bytes", len),
+            CtmpError::UnknownOptions(options) => write!(f, "Unknown option bits: {:#04x}", options),
+            CtmpError::NonZeroPadding(padding) => write!(f, "Non-zero padding: {:#06x}", padding),
+            CtmpError::BadTrailer => write!(f, "Missing or invalid HMAC trailer"),
+            CtmpError::Resynced { skipped } => write!(f, "Skipped {} bytes to find the next packet", skipped),
+            CtmpError::PeerClosed => write!(f, "Peer closed the connection"),
+            CtmpError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CtmpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CtmpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CtmpError {
+    fn from(e: io::Error) -> CtmpError {
+        CtmpError::Io(e)
+    }
+}
+
+impl From<CtmpError> for io::Error {
+    fn from(e: CtmpError) -> io::Error {
+        match e {
+            CtmpError::Io(e) => e,
+            CtmpError::PeerClosed => io::Error::from(io::ErrorKind::UnexpectedEof),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Framing for the CTMP protocol.
+//!
+//! A CTMP packet is an 8 byte header followed by `length` bytes of data:
+//!
+//! ```text
+//!  0        1        2        3        4        5        6        7
+//! +--------+--------+--------+--------+--------+--------+--------+--------+
+//! | magic  |options |     length      |    checksum     |     padding     |
+//! +--------+--------+--------+--------+--------+--------+--------+--------+
+//! ```
+//!
+//! All multi-byte fields are big-endian.
+
+mod checksum;
+mod decoder;
+mod error;
+mod packet;
+mod trailer;
+
+pub use checksum::{Checksum, Crc32c, HmacSha256, Rfc1071};
+pub use decoder::{
+    BadChecksumPolicy, DEFAULT_READ_LEN, DEFAULT_RESYNC_MAX_LENGTH, Decoder, HeaderValidation, ResyncPolicy,
+};
+pub use error::CtmpError;
+pub use packet::{
+    ChecksumPolicy, HEADER_LEN, Header, MAGIC, MAX_PACKET_LEN, OPTION_CONTROL, OPTION_SENSITIVE,
+    Packet, calculate_checksum,
+};
+pub use trailer::{HmacTrailer, TRAILER_LEN};
@@ -0,0 +1,280 @@
+use std::io::Error;
+use std::str::FromStr;
+
+use crate::checksum::{Checksum, Rfc1071};
+use crate::error::CtmpError;
+
+/// Value of the first byte of every CTMP packet.
+pub const MAGIC: u8 = 0xCC;
+
+/// Length of the fixed size packet header.
+pub const HEADER_LEN: usize = 8;
+
+/// Largest possible packet: a full header plus the maximum `length` field.
+pub const MAX_PACKET_LEN: usize = HEADER_LEN + u16::MAX as usize;
+
+/// Option bit marking a packet as 'sensitive'. Sensitive packets must carry a
+/// valid checksum.
+pub const OPTION_SENSITIVE: u8 = 0x40;
+
+/// Option bit marking a packet as a 'control' packet, addressed to the proxy
+/// rather than forwarded, on connections that must authenticate.
+pub const OPTION_CONTROL: u8 = 0x80;
+
+/// Every option bit with a defined meaning.
+const KNOWN_OPTIONS: u8 = OPTION_SENSITIVE | OPTION_CONTROL;
+
+/// Value substituted for the checksum field while the checksum is calculated.
+pub(crate) const CHECKSUM_PLACEHOLDER: u16 = 0xCCCC;
+
+/// Byte offset of the checksum field within the header.
+pub(crate) const CHECKSUM_OFFSET: usize = 4;
+
+/// Which packets must carry a valid checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumPolicy {
+    /// Every packet.
+    All,
+    /// Packets whose checksum field isn't zero, i.e. whose sender filled it in.
+    NonZero,
+    /// Only 'sensitive' packets.
+    #[default]
+    Sensitive,
+    /// No packets. Checksums aren't checked.
+    Off,
+}
+
+impl ChecksumPolicy {
+    /// Checks whether the packet described by `header` must carry a valid checksum.
+    pub fn requires_checksum(&self, header: &Header) -> bool {
+        match self {
+            ChecksumPolicy::All => true,
+            ChecksumPolicy::NonZero => header.checksum != 0,
+            ChecksumPolicy::Sensitive => header.is_sensitive(),
+            ChecksumPolicy::Off => false,
+        }
+    }
+}
+
+impl FromStr for ChecksumPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ChecksumPolicy, Error> {
+        match s {
+            "all" => Ok(ChecksumPolicy::All),
+            "non-zero" => Ok(ChecksumPolicy::NonZero),
+            "sensitive" => Ok(ChecksumPolicy::Sensitive),
+            "off" => Ok(ChecksumPolicy::Off),
+            _ => Err(Error::other(format!("Unknown checksum policy: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub magic: u8,
+    pub options: u8,
+    /// Length of the packet data, excluding the header.
+    pub length: u16,
+    pub checksum: u16,
+    pub padding: u16,
+}
+
+impl Header {
+    /// Reads a header from the start of `bytes`.
+    ///
+    /// Returns `None` if there are fewer than `HEADER_LEN` bytes. The magic byte
+    /// is not checked.
+    pub fn parse(bytes: &[u8]) -> Option<Header> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+
+        Some(Header {
+            magic: bytes[0],
+            options: bytes[1],
+            length: u16::from_be_bytes([bytes[2], bytes[3]]),
+            checksum: u16::from_be_bytes([bytes[4], bytes[5]]),
+            padding: u16::from_be_bytes([bytes[6], bytes[7]]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let length = self.length.to_be_bytes();
+        let checksum = self.checksum.to_be_bytes();
+        let padding = self.padding.to_be_bytes();
+
+        [
+            self.magic,
+            self.options,
+            length[0],
+            length[1],
+            checksum[0],
+            checksum[1],
+            padding[0],
+            padding[1],
+        ]
+    }
+
+    /// Length of the whole packet described by this header.
+    pub fn packet_len(&self) -> usize {
+        HEADER_LEN + self.length as usize
+    }
+
+    pub fn is_sensitive(&self) -> bool {
+        self.options & OPTION_SENSITIVE > 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.options & OPTION_CONTROL > 0
+    }
+
+    /// Checks the header follows the spec beyond what's needed to frame the
+    /// packet: only known option bits are set, and the padding is zero.
+    pub fn validate(&self) -> Result<(), CtmpError> {
+        let unknown = self.options & !KNOWN_OPTIONS;
+        if unknown != 0 {
+            return Err(CtmpError::UnknownOptions(unknown));
+        }
+
+        if self.padding != 0 {
+            return Err(CtmpError::NonZeroPadding(self.padding));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    /// Builds a packet around `data`, filling in the length and checksum.
+    ///
+    /// Returns error if `data` doesn't fit in the 16-bit length field.
+    pub fn new(options: u8, data: Vec<u8>) -> Result<Packet, CtmpError> {
+        let length = u16::try_from(data.len()).map_err(|_| CtmpError::Oversize(data.len()))?;
+
+        let mut packet = Packet {
+            header: Header {
+                magic: MAGIC,
+                options,
+                length,
+                checksum: 0,
+                padding: 0,
+            },
+            data,
+        };
+        packet.header.checksum = packet.calculate_checksum();
+
+        Ok(packet)
+    }
+
+    /// Parses the packet at the start of `bytes`, checking the checksum of
+    /// 'sensitive' packets. Any bytes after the packet are ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Packet, CtmpError> {
+        Packet::parse_with(bytes, ChecksumPolicy::default())
+    }
+
+    /// Parses the packet at the start of `bytes`. Any bytes after the packet
+    /// are ignored.
+    ///
+    /// Returns error if:
+    ///  - `bytes` doesn't hold a complete packet.
+    ///  - Packet magic byte is incorrect.
+    ///  - `checksum_policy` requires a checksum and the checksum field doesn't
+    ///    match the calculated checksum.
+    pub fn parse_with(bytes: &[u8], checksum_policy: ChecksumPolicy) -> Result<Packet, CtmpError> {
+        let header = Header::parse(bytes).ok_or(CtmpError::Incomplete)?;
+
+        if header.magic != MAGIC {
+            return Err(CtmpError::BadMagic(header.magic));
+        }
+
+        if bytes.len() < header.packet_len() {
+            return Err(CtmpError::Incomplete);
+        }
+
+        let packet = Packet {
+            header,
+            data: bytes[HEADER_LEN..header.packet_len()].to_vec(),
+        };
+
+        if checksum_policy.requires_checksum(&header) {
+            packet.check_checksum()?;
+        }
+
+        Ok(packet)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&self.header.encode());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Length of the encoded packet, including the header. The length of the
+    /// data alone is `data.len()`.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.data.len()
+    }
+
+    pub fn calculate_checksum(&self) -> u16 {
+        self.calculate_checksum_with(&Rfc1071)
+    }
+
+    pub fn calculate_checksum_with(&self, checksum: &dyn Checksum) -> u16 {
+        checksum.calculate(&self.encode())
+    }
+
+    /// Calculates the checksum of the packet and compares it to the expected
+    /// checksum defined within the packet.
+    pub fn check_checksum(&self) -> Result<(), CtmpError> {
+        self.check_checksum_with(&Rfc1071)
+    }
+
+    /// Like `check_checksum`, with the checksum calculated by `checksum`.
+    pub fn check_checksum_with(&self, checksum: &dyn Checksum) -> Result<(), CtmpError> {
+        let expected = self.header.checksum;
+        let actual = self.calculate_checksum_with(checksum);
+
+        if expected == actual {
+            return Ok(());
+        }
+
+        Err(CtmpError::BadChecksum { expected, actual })
+    }
+}
+
+/// Calculates the packet's checksum based on the 'Internet Checksum' standard
+/// defined in RFC 1071. Checksum is calculated with `0xCCCC` replacing checksum
+/// field.
+pub fn calculate_checksum(packet_data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    for i in (0..packet_data.len()).step_by(2) {
+        let mut word: u16 = (packet_data[i] as u16) << 8;
+
+        if i + 1 < packet_data.len() {
+            word |= packet_data[i + 1] as u16;
+        }
+
+        // Ignore the checksum field.
+        if i == CHECKSUM_OFFSET {
+            word = CHECKSUM_PLACEHOLDER;
+        }
+
+        sum += word as u32;
+
+        // Fold the carry bits.
+        if sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + 1;
+        }
+    }
+
+    !sum as u16
+}
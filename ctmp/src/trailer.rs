@@ -0,0 +1,155 @@
+use ring::hmac;
+
+use crate::error::CtmpError;
+use crate::packet::{CHECKSUM_OFFSET, CHECKSUM_PLACEHOLDER, Packet};
+
+/// Length of the HMAC-SHA256 trailer at the end of a signed packet's data.
+pub const TRAILER_LEN: usize = 32;
+
+/// Signs and verifies the HMAC-SHA256 trailer that authenticates 'sensitive'
+/// packets.
+///
+/// The trailer is the last `TRAILER_LEN` bytes of the packet data, and is
+/// counted in the length field. It's calculated over the header, with
+/// `0xCCCC` replacing the checksum field, followed by the rest of the data.
+/// Unlike the checksum, it can only be made by someone who knows the key.
+#[derive(Debug, Clone)]
+pub struct HmacTrailer {
+    key: hmac::Key,
+}
+
+impl HmacTrailer {
+    pub fn new(key: &[u8]) -> HmacTrailer {
+        HmacTrailer {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+        }
+    }
+
+    /// Appends a trailer to the packet's data.
+    ///
+    /// Returns error if the data with the trailer doesn't fit in the 16-bit
+    /// length field.
+    pub fn sign(&self, packet: &mut Packet) -> Result<(), CtmpError> {
+        let len = packet.data.len() + TRAILER_LEN;
+        packet.header.length = u16::try_from(len).map_err(|_| CtmpError::Oversize(len))?;
+
+        let tag = hmac::sign(&self.key, &message(packet, packet.data.len()));
+        packet.data.extend_from_slice(tag.as_ref());
+
+        Ok(())
+    }
+
+    /// Replaces the packet's trailer with one signed with this key.
+    ///
+    /// Returns error if the packet is too short to have a trailer.
+    pub fn resign(&self, packet: &mut Packet) -> Result<(), CtmpError> {
+        let signed_len = packet.data.len().checked_sub(TRAILER_LEN).ok_or(CtmpError::BadTrailer)?;
+
+        let tag = hmac::sign(&self.key, &message(packet, signed_len));
+        packet.data[signed_len..].copy_from_slice(tag.as_ref());
+
+        Ok(())
+    }
+
+    /// Checks the packet's trailer was signed with this key.
+    ///
+    /// Returns error if the packet is too short to have a trailer, or the
+    /// trailer is wrong.
+    pub fn verify(&self, packet: &Packet) -> Result<(), CtmpError> {
+        let signed_len = packet.data.len().checked_sub(TRAILER_LEN).ok_or(CtmpError::BadTrailer)?;
+
+        // Compares in constant time.
+        hmac::verify(&self.key, &message(packet, signed_len), &packet.data[signed_len..])
+            .map_err(|_| CtmpError::BadTrailer)
+    }
+}
+
+/// The bytes a trailer is calculated over: the header and the first
+/// `signed_len` bytes of data.
+fn message(packet: &Packet, signed_len: usize) -> Vec<u8> {
+    let mut header = packet.header.encode();
+    header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&CHECKSUM_PLACEHOLDER.to_be_bytes());
+
+    let mut message = Vec::with_capacity(header.len() + signed_len);
+    message.extend_from_slice(&header);
+    message.extend_from_slice(&packet.data[..signed_len]);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::OPTION_SENSITIVE;
+
+    fn signed(trailer: &HmacTrailer) -> Packet {
+        let mut packet = Packet::new(OPTION_SENSITIVE, b"secret".to_vec()).unwrap();
+        trailer.sign(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn sign_then_verify() {
+        let trailer = HmacTrailer::new(b"key");
+        let packet = signed(&trailer);
+
+        assert_eq!(packet.data.len(), b"secret".len() + TRAILER_LEN);
+        assert_eq!(packet.header.length as usize, packet.data.len());
+        assert!(trailer.verify(&packet).is_ok());
+    }
+
+    #[test]
+    fn verify_ignores_checksum_field() {
+        let trailer = HmacTrailer::new(b"key");
+        let mut packet = signed(&trailer);
+        packet.header.checksum = packet.calculate_checksum();
+
+        assert!(trailer.verify(&packet).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_tampered_data() {
+        let trailer = HmacTrailer::new(b"key");
+        let mut packet = signed(&trailer);
+        packet.data[0] ^= 0x01;
+
+        assert!(matches!(trailer.verify(&packet), Err(CtmpError::BadTrailer)));
+    }
+
+    #[test]
+    fn verify_rejects_tampered_header() {
+        let trailer = HmacTrailer::new(b"key");
+        let mut packet = signed(&trailer);
+        packet.header.options ^= 0x01;
+
+        assert!(matches!(trailer.verify(&packet), Err(CtmpError::BadTrailer)));
+    }
+
+    #[test]
+    fn verify_rejects_packet_too_short_for_trailer() {
+        let trailer = HmacTrailer::new(b"key");
+        let packet = Packet::new(OPTION_SENSITIVE, vec![0; TRAILER_LEN - 1]).unwrap();
+
+        assert!(matches!(trailer.verify(&packet), Err(CtmpError::BadTrailer)));
+    }
+
+    #[test]
+    fn resign_replaces_trailer() {
+        let old = HmacTrailer::new(b"old key");
+        let new = HmacTrailer::new(b"new key");
+        let mut packet = signed(&old);
+
+        new.resign(&mut packet).unwrap();
+
+        assert_eq!(packet.data.len(), b"secret".len() + TRAILER_LEN);
+        assert!(new.verify(&packet).is_ok());
+        assert!(matches!(old.verify(&packet), Err(CtmpError::BadTrailer)));
+    }
+
+    #[test]
+    fn resign_rejects_packet_too_short_for_trailer() {
+        let trailer = HmacTrailer::new(b"key");
+        let mut packet = Packet::new(OPTION_SENSITIVE, vec![0; TRAILER_LEN - 1]).unwrap();
+
+        assert!(matches!(trailer.resign(&mut packet), Err(CtmpError::BadTrailer)));
+    }
+}
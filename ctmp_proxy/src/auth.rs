@@ -0,0 +1,158 @@
+use std::io::Error;
+
+use ctmp::{OPTION_CONTROL, Packet};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+
+/// Length of the random challenge sent for HMAC authentication.
+const CHALLENGE_LEN: usize = 32;
+
+/// How clients of a listener authenticate before they're read from or sent
+/// packets.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum AuthConfig {
+    /// The client's first packet is a control packet holding the token.
+    Token(String),
+    /// The proxy sends a control packet holding a random challenge, and the
+    /// client answers with a control packet holding the HMAC-SHA256 of the
+    /// challenge, keyed with this key.
+    Hmac(String),
+}
+
+/// Authentication a client hasn't completed yet.
+pub struct Handshake {
+    expected: Expected,
+    /// Control packet to send the client when it connects, if any.
+    challenge: Option<Packet>,
+}
+
+enum Expected {
+    Token(Vec<u8>),
+    Hmac { key: hmac::Key, challenge: Vec<u8> },
+}
+
+impl Handshake {
+    /// Starts authenticating a client, picking a new challenge if needed.
+    pub fn new(config: &AuthConfig) -> Result<Handshake, Error> {
+        match config {
+            AuthConfig::Token(token) => Ok(Handshake {
+                expected: Expected::Token(token.as_bytes().to_vec()),
+                challenge: None,
+            }),
+            AuthConfig::Hmac(key) => {
+                let mut challenge = vec![0; CHALLENGE_LEN];
+                SystemRandom::new()
+                    .fill(&mut challenge)
+                    .map_err(|_| Error::other("Failed to generate challenge"))?;
+
+                Ok(Handshake {
+                    challenge: Some(Packet::new(OPTION_CONTROL, challenge.clone())?),
+                    expected: Expected::Hmac {
+                        key: hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+                        challenge,
+                    },
+                })
+            }
+        }
+    }
+
+    /// Takes the control packet to send the client when it connects, if any.
+    pub fn take_challenge(&mut self) -> Option<Packet> {
+        self.challenge.take()
+    }
+
+    /// Checks whether `packet`, the first packet from the client, proves it
+    /// knows the secret.
+    pub fn verify(&self, packet: &Packet) -> bool {
+        if !packet.header.is_control() {
+            return false;
+        }
+
+        match &self.expected {
+            Expected::Token(token) => constant_time_eq(token, &packet.data),
+            Expected::Hmac { key, challenge } => hmac::verify(key, challenge, &packet.data).is_ok(),
+        }
+    }
+}
+
+/// Compares two byte strings in time that doesn't depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(data: &[u8]) -> Packet {
+        Packet::new(OPTION_CONTROL, data.to_vec()).unwrap()
+    }
+
+    fn token_handshake() -> Handshake {
+        Handshake::new(&AuthConfig::Token("secret".to_string())).unwrap()
+    }
+
+    fn hmac_handshake() -> Handshake {
+        Handshake::new(&AuthConfig::Hmac("key".to_string())).unwrap()
+    }
+
+    /// Control packet answering the handshake's challenge with an HMAC keyed
+    /// with "key".
+    fn answer(handshake: &mut Handshake) -> Packet {
+        let challenge = handshake.take_challenge().unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"key");
+        control(hmac::sign(&key, &challenge.data).as_ref())
+    }
+
+    #[test]
+    fn accepts_correct_token() {
+        let mut handshake = token_handshake();
+
+        assert!(handshake.take_challenge().is_none());
+        assert!(handshake.verify(&control(b"secret")));
+    }
+
+    #[test]
+    fn rejects_wrong_token() {
+        assert!(!token_handshake().verify(&control(b"secreT")));
+    }
+
+    #[test]
+    fn rejects_token_of_wrong_length() {
+        let handshake = token_handshake();
+
+        assert!(!handshake.verify(&control(b"secre")));
+        assert!(!handshake.verify(&control(b"secrets")));
+        assert!(!handshake.verify(&control(b"")));
+    }
+
+    #[test]
+    fn rejects_packet_that_is_not_control() {
+        assert!(!token_handshake().verify(&Packet::new(0, b"secret".to_vec()).unwrap()));
+
+        let mut handshake = hmac_handshake();
+        let mut packet = answer(&mut handshake);
+        packet.header.options = 0;
+        assert!(!handshake.verify(&packet));
+    }
+
+    #[test]
+    fn accepts_hmac_of_challenge() {
+        let mut handshake = hmac_handshake();
+        let packet = answer(&mut handshake);
+
+        assert!(handshake.verify(&packet));
+    }
+
+    #[test]
+    fn rejects_hmac_of_another_challenge() {
+        let mut other = hmac_handshake();
+        let packet = answer(&mut other);
+
+        let mut handshake = hmac_handshake();
+        assert!(handshake.take_challenge().is_some());
+        assert!(!handshake.verify(&packet));
+    }
+}
@@ -0,0 +1,522 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::Parser;
+use ctmp::{
+    BadChecksumPolicy, Checksum, ChecksumPolicy, Crc32c, DEFAULT_READ_LEN, DEFAULT_RESYNC_MAX_LENGTH, HeaderValidation,
+    HmacSha256, ResyncPolicy, Rfc1071,
+};
+use ipnet::IpNet;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::auth::AuthConfig;
+use crate::destination::{DEFAULT_QUEUE_CAPACITY, SensitivePolicy, SlowConsumerPolicy};
+use crate::source::SourcePolicy;
+use crate::stream::Endpoint;
+use crate::tls;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const SOURCE_PORT: u16 = 33333;
+const DESTINATION_PORT: u16 = 44444;
+
+/// Forwards CTMP packets from source clients to destination clients.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML config file. Options given on the command line override it.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Check the config is valid, then exit.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Lowest level of log messages shown: off, error, warn, info, debug or trace.
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Default slow consumer policy: drop-oldest, drop-newest, disconnect or backpressure.
+    #[arg(long)]
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// File that rejected packets are appended to, with the time, client and
+    /// reason.
+    #[arg(long)]
+    pub quarantine: Option<PathBuf>,
+
+    /// Address a source listener binds to, e.g. `127.0.0.1:33333`,
+    /// `[::]:33333` or `unix:/run/ctmp/source.sock`. Repeat for several
+    /// listeners. Replaces the config file's source listeners.
+    #[arg(long)]
+    pub source_listen: Vec<Endpoint>,
+
+    /// What happens when a source connects while another is connected:
+    /// concurrent, reject, preempt or standby.
+    #[arg(long)]
+    pub source_policy: Option<SourcePolicy>,
+
+    /// Number of bytes requested on each read from a source.
+    #[arg(long)]
+    pub read_buffer_size: Option<usize>,
+
+    /// Which packets must carry a valid checksum: all, non-zero (packets
+    /// whose checksum field is set), sensitive or off. Source listeners that
+    /// set their own policy keep it.
+    #[arg(long)]
+    pub checksum: Option<ChecksumPolicy>,
+
+    /// What happens to a packet whose checksum is required but wrong: reject
+    /// (drop it) or repair (forward it with the checksum recalculated).
+    #[arg(long)]
+    pub bad_checksum: Option<BadChecksumPolicy>,
+
+    /// How strictly source packet headers are checked for unknown option bits
+    /// and non-zero padding: off, lenient (forward and count them) or strict
+    /// (drop them).
+    #[arg(long)]
+    pub header_validation: Option<HeaderValidation>,
+
+    /// How a corrupted source stream is recovered: off (discard buffered
+    /// data), magic or checksum (skip to the next plausible header).
+    #[arg(long)]
+    pub resync: Option<ResyncPolicy>,
+
+    /// Longest packet data accepted from a source, in bytes.
+    #[arg(long)]
+    pub max_length: Option<u16>,
+
+    /// Longest packet data accepted as the first packet found while
+    /// resynchronizing a source stream, in bytes.
+    #[arg(long)]
+    pub resync_max_length: Option<u16>,
+
+    /// Address a destination listener binds to. Repeat for several listeners.
+    /// Replaces the config file's destination listeners.
+    #[arg(long)]
+    pub destination_listen: Vec<Endpoint>,
+
+    /// Number of packets each destination can have waiting to be written.
+    #[arg(long)]
+    pub queue_capacity: Option<usize>,
+
+    /// Slow consumer policy for every destination listener, overriding
+    /// `--slow-consumer`.
+    #[arg(long)]
+    pub destination_slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// What untrusted destinations get instead of sensitive packets:
+    /// forward, withhold or redact.
+    #[arg(long)]
+    pub untrusted_sensitive: Option<SensitivePolicy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    /// Slow consumer policy for destination listeners that don't set their own.
+    #[serde(deserialize_with = "from_str")]
+    pub slow_consumer: SlowConsumerPolicy,
+    /// File that rejected packets are appended to.
+    pub quarantine: Option<PathBuf>,
+    pub source: SourceConfig,
+    pub destination: DestinationConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    pub listen: Vec<ListenerConfig>,
+    #[serde(deserialize_with = "from_str")]
+    pub policy: SourcePolicy,
+    pub read_buffer_size: usize,
+    /// Checksum policy for source listeners that don't set their own.
+    #[serde(deserialize_with = "from_str")]
+    pub checksum: ChecksumPolicy,
+    #[serde(deserialize_with = "from_str")]
+    pub bad_checksum: BadChecksumPolicy,
+    #[serde(deserialize_with = "from_str")]
+    pub header_validation: HeaderValidation,
+    #[serde(deserialize_with = "from_str")]
+    pub resync: ResyncPolicy,
+    /// Longest packet data accepted. Longer packets are treated as a
+    /// corrupted stream.
+    pub max_length: u16,
+    /// Longest packet data accepted as the first packet found while
+    /// resynchronizing. Longer candidates are skipped, so a stray magic byte
+    /// followed by a large length can't hold back the packets behind it.
+    pub resync_max_length: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationConfig {
+    pub listen: Vec<ListenerConfig>,
+    pub queue_capacity: usize,
+    /// What untrusted destinations get instead of 'sensitive' packets.
+    #[serde(deserialize_with = "from_str")]
+    pub untrusted_sensitive: SensitivePolicy,
+    /// Destinations connecting from these networks are trusted.
+    pub trusted_networks: Vec<IpNet>,
+    /// Destinations whose client certificate has one of these names, as its
+    /// CN or a SAN, are trusted.
+    pub trusted_identities: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Socket address, or `unix:` followed by a socket file path.
+    #[serde(deserialize_with = "from_str")]
+    pub address: Endpoint,
+    /// Stops an IPv6 listener from also accepting IPv4 connections.
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// If set, only clients connecting from these networks are accepted.
+    /// TCP listeners only.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Clients connecting from these networks are rejected, even if allowed.
+    /// TCP listeners only.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Permissions of the socket file, e.g. `0o660`. Unix domain socket
+    /// listeners only.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Encrypts connections to the listener with TLS.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Makes clients authenticate before they're read from or sent packets.
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Which packets from sources accepted by this listener must carry a
+    /// valid checksum. Source listeners only.
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub checksum: Option<ChecksumPolicy>,
+    /// Algorithm packet checksums are calculated with. Packets from sources
+    /// are checked with their listener's algorithm. Packets with a valid
+    /// checksum are sent to destinations with the checksum recalculated with
+    /// the destination listener's algorithm.
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Key of the HMAC-SHA256 trailer that authenticates 'sensitive' packets.
+    /// Sensitive packets from sources must carry a trailer signed with their
+    /// listener's key, checked instead of the checksum. Destinations get them
+    /// re-signed with their listener's key.
+    #[serde(default)]
+    pub sensitive_hmac: Option<String>,
+    /// Slow consumer policy for destinations accepted by this listener.
+    /// Destination listeners only.
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+    /// Names a destination's client certificate must have, as its CN or a
+    /// SAN, to be sent packets. Any verified certificate is accepted if empty.
+    /// Destination listeners with `tls.client_ca` only.
+    #[serde(default)]
+    pub identities: Vec<String>,
+    /// Trusts every destination accepted by this listener with 'sensitive'
+    /// packets. Destination listeners only.
+    #[serde(default)]
+    pub trusted: bool,
+}
+
+/// Algorithm the checksum field of packets is calculated with. In TOML, e.g.
+/// `checksum_algorithm = "crc32c"` or `checksum_algorithm = { hmac-sha256 = "key" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ChecksumAlgorithm {
+    /// The 'Internet Checksum' from RFC 1071.
+    #[default]
+    Rfc1071,
+    /// CRC-32C, truncated to 16 bits.
+    Crc32c,
+    /// HMAC-SHA256 keyed with this key, truncated to 16 bits.
+    HmacSha256(String),
+}
+
+impl ChecksumAlgorithm {
+    pub fn build(&self) -> Arc<dyn Checksum> {
+        match self {
+            ChecksumAlgorithm::Rfc1071 => Arc::new(Rfc1071),
+            ChecksumAlgorithm::Crc32c => Arc::new(Crc32c),
+            ChecksumAlgorithm::HmacSha256(key) => Arc::new(HmacSha256::new(key.as_bytes())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first.
+    pub cert: PathBuf,
+    /// PEM file with the certificate's private key.
+    pub key: PathBuf,
+    /// PEM file with the CAs trusted to sign client certificates. If set,
+    /// clients must present a certificate signed by one of them.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            log_level: LevelFilter::Info,
+            slow_consumer: SlowConsumerPolicy::default(),
+            quarantine: None,
+            source: SourceConfig::default(),
+            destination: DestinationConfig::default(),
+        }
+    }
+}
+
+impl Default for SourceConfig {
+    fn default() -> SourceConfig {
+        SourceConfig {
+            listen: vec![ListenerConfig::new(Endpoint::Tcp(SocketAddr::new(LOCALHOST, SOURCE_PORT)))],
+            policy: SourcePolicy::default(),
+            read_buffer_size: DEFAULT_READ_LEN,
+            checksum: ChecksumPolicy::default(),
+            bad_checksum: BadChecksumPolicy::default(),
+            header_validation: HeaderValidation::default(),
+            resync: ResyncPolicy::default(),
+            max_length: u16::MAX,
+            resync_max_length: DEFAULT_RESYNC_MAX_LENGTH,
+        }
+    }
+}
+
+impl Default for DestinationConfig {
+    fn default() -> DestinationConfig {
+        DestinationConfig {
+            listen: vec![ListenerConfig::new(Endpoint::Tcp(SocketAddr::new(LOCALHOST, DESTINATION_PORT)))],
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            untrusted_sensitive: SensitivePolicy::default(),
+            trusted_networks: Vec::new(),
+            trusted_identities: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file given in `args`, if any, then applies the options
+    /// given on the command line.
+    ///
+    /// Returns error if the config file can't be read or the config is invalid.
+    pub fn load(args: &Args) -> Result<Config, Error> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| Error::other(format!("Failed to read {}: {}", path.display(), e)))?;
+                toml::from_str(&contents)
+                    .map_err(|e| Error::other(format!("Failed to parse {}: {}", path.display(), e)))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(log_level) = args.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(slow_consumer) = args.slow_consumer {
+            self.slow_consumer = slow_consumer;
+        }
+        if let Some(quarantine) = &args.quarantine {
+            self.quarantine = Some(quarantine.clone());
+        }
+        if !args.source_listen.is_empty() {
+            self.source.listen = args.source_listen.iter().cloned().map(ListenerConfig::new).collect();
+        }
+        if let Some(policy) = args.source_policy {
+            self.source.policy = policy;
+        }
+        if let Some(read_buffer_size) = args.read_buffer_size {
+            self.source.read_buffer_size = read_buffer_size;
+        }
+        if let Some(checksum) = args.checksum {
+            self.source.checksum = checksum;
+        }
+        if let Some(bad_checksum) = args.bad_checksum {
+            self.source.bad_checksum = bad_checksum;
+        }
+        if let Some(header_validation) = args.header_validation {
+            self.source.header_validation = header_validation;
+        }
+        if let Some(resync) = args.resync {
+            self.source.resync = resync;
+        }
+        if let Some(max_length) = args.max_length {
+            self.source.max_length = max_length;
+        }
+        if let Some(resync_max_length) = args.resync_max_length {
+            self.source.resync_max_length = resync_max_length;
+        }
+        if !args.destination_listen.is_empty() {
+            self.destination.listen = args.destination_listen.iter().cloned().map(ListenerConfig::new).collect();
+        }
+        if let Some(queue_capacity) = args.queue_capacity {
+            self.destination.queue_capacity = queue_capacity;
+        }
+        if args.destination_slow_consumer.is_some() {
+            for listener in &mut self.destination.listen {
+                listener.slow_consumer = args.destination_slow_consumer;
+            }
+        }
+        if let Some(untrusted_sensitive) = args.untrusted_sensitive {
+            self.destination.untrusted_sensitive = untrusted_sensitive;
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.source.read_buffer_size == 0 {
+            return Err(Error::other("source.read_buffer_size must be greater than 0"));
+        }
+
+        if self.destination.queue_capacity == 0 {
+            return Err(Error::other("destination.queue_capacity must be greater than 0"));
+        }
+
+        if self.source.listen.is_empty() {
+            return Err(Error::other("At least one source listener is required"));
+        }
+
+        if self.destination.listen.is_empty() {
+            return Err(Error::other("At least one destination listener is required"));
+        }
+
+        let mut addresses = HashSet::new();
+        for listener in self.source.listen.iter().chain(&self.destination.listen) {
+            if !addresses.insert(&listener.address) {
+                return Err(Error::other(format!("More than one listener binds to {}", listener.address)));
+            }
+        }
+
+        if let Some(listener) = self.destination.listen.iter().find(|listener| listener.checksum.is_some()) {
+            return Err(Error::other(format!(
+                "Destination listener {} can't have a checksum policy",
+                listener.address
+            )));
+        }
+
+        if let Some(listener) = self.source.listen.iter().find(|listener| listener.slow_consumer.is_some()) {
+            return Err(Error::other(format!(
+                "Source listener {} can't have a slow consumer policy",
+                listener.address
+            )));
+        }
+
+        if let Some(listener) = self.source.listen.iter().find(|listener| !listener.identities.is_empty()) {
+            return Err(Error::other(format!("Source listener {} can't have identities", listener.address)));
+        }
+
+        if let Some(listener) = self.source.listen.iter().find(|listener| listener.trusted) {
+            return Err(Error::other(format!("Source listener {} can't be trusted", listener.address)));
+        }
+
+        if let Some(listener) = self
+            .destination
+            .listen
+            .iter()
+            .find(|listener| !listener.identities.is_empty() && !listener.verifies_clients())
+        {
+            return Err(Error::other(format!(
+                "Destination listener {} needs tls.client_ca to check identities",
+                listener.address
+            )));
+        }
+
+        for listener in self.source.listen.iter().chain(&self.destination.listen) {
+            match (&listener.address, listener.mode) {
+                (Endpoint::Tcp(_), Some(_)) => {
+                    return Err(Error::other(format!("TCP listener {} can't have a mode", listener.address)));
+                }
+                (Endpoint::Unix(_), Some(mode)) if mode > 0o7777 => {
+                    return Err(Error::other(format!("Invalid mode for listener {}: {:o}", listener.address, mode)));
+                }
+                _ => {}
+            }
+
+            if matches!(listener.address, Endpoint::Unix(_)) && !(listener.allow.is_empty() && listener.deny.is_empty()) {
+                return Err(Error::other(format!(
+                    "Unix domain socket listener {} can't have allow or deny lists",
+                    listener.address
+                )));
+            }
+
+            if let Some(tls) = &listener.tls {
+                tls::server_config(tls)
+                    .map_err(|e| Error::other(format!("Invalid TLS config for listener {}: {}", listener.address, e)))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SourceConfig {
+    /// Checksum policy for sources accepted by the listener at `listener`.
+    pub fn checksum_for(&self, listener: &Endpoint) -> ChecksumPolicy {
+        self.listen
+            .iter()
+            .find(|config| config.address == *listener)
+            .and_then(|config| config.checksum)
+            .unwrap_or(self.checksum)
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(address: Endpoint) -> ListenerConfig {
+        ListenerConfig {
+            address,
+            ipv6_only: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            mode: None,
+            tls: None,
+            auth: None,
+            checksum: None,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            sensitive_hmac: None,
+            slow_consumer: None,
+            identities: Vec::new(),
+            trusted: false,
+        }
+    }
+
+    /// Checks whether clients must present a certificate signed by a trusted CA.
+    pub fn verifies_clients(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some())
+    }
+}
+
+/// Deserializes a value from a string using its `FromStr` implementation.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = Error>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// Deserializes an optional value from a string using its `FromStr` implementation.
+fn from_str_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = Error>,
+{
+    let s = Option::<String>::deserialize(deserializer)?;
+    s.map(|s| s.parse()).transpose().map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ctmp::{Checksum, CtmpError, Decoder, HmacTrailer, MAX_PACKET_LEN, Packet, Rfc1071};
+
+use crate::auth::Handshake;
+use crate::stream::{Endpoint, Stream};
+use crate::tls::Identity;
+
+/// Number of packets a destination can have waiting to be written.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// What happens when a packet is sent to a destination whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued packet to make room for the new one.
+    DropOldest,
+    /// Drop the new packet.
+    #[default]
+    DropNewest,
+    /// Close the destination.
+    Disconnect,
+    /// Queue the packet anyway and stop reading sources until the destination
+    /// catches up.
+    Backpressure,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SlowConsumerPolicy, Error> {
+        match s {
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "drop-newest" => Ok(SlowConsumerPolicy::DropNewest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "backpressure" => Ok(SlowConsumerPolicy::Backpressure),
+            _ => Err(Error::other(format!("Unknown slow consumer policy: {}", s))),
+        }
+    }
+}
+
+/// What untrusted destinations get instead of 'sensitive' packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SensitivePolicy {
+    /// The packet as it is, like trusted destinations.
+    #[default]
+    Forward,
+    /// Nothing.
+    Withhold,
+    /// The packet with its data zeroed.
+    Redact,
+}
+
+impl FromStr for SensitivePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SensitivePolicy, Error> {
+        match s {
+            "forward" => Ok(SensitivePolicy::Forward),
+            "withhold" => Ok(SensitivePolicy::Withhold),
+            "redact" => Ok(SensitivePolicy::Redact),
+            _ => Err(Error::other(format!("Unknown sensitive packet policy: {}", s))),
+        }
+    }
+}
+
+/// A connected destination client and the packets waiting to be written to it.
+///
+/// Packets are written without blocking, so a destination that isn't reading
+/// only fills its own queue.
+pub struct Destination {
+    pub stream: Stream,
+    pub address: Endpoint,
+    /// Address of the listener that accepted the destination.
+    pub listener: Endpoint,
+    /// Identity from the destination's client certificate, set once the TLS
+    /// handshake is complete.
+    pub identity: Option<Identity>,
+    /// Whether the destination is waiting to be identified. Packets aren't
+    /// sent to it until then.
+    pub unidentified: bool,
+    /// Set until the destination authenticates. Packets aren't sent to it
+    /// until then.
+    pub handshake: Option<Handshake>,
+    /// Frames the packets the destination sends to authenticate.
+    decoder: Decoder,
+    /// Whether the destination is sent 'sensitive' packets as they are.
+    pub trusted: bool,
+    /// Encoded packets waiting to be written. The bytes are shared with every
+    /// other destination the packet was sent to.
+    queue: VecDeque<Rc<[u8]>>,
+    /// Number of packets the queue holds before the slow consumer policy applies.
+    pub capacity: usize,
+    /// Number of bytes of the front packet already written.
+    written: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Number of packets dropped because the queue was full.
+    pub dropped: u64,
+    /// Algorithm the checksums of packets sent to the destination are
+    /// calculated with.
+    pub checksum: Arc<dyn Checksum>,
+    /// If set, 'sensitive' packets whose HMAC trailer was checked are
+    /// re-signed with this key for the destination.
+    pub trailer: Option<Arc<HmacTrailer>>,
+}
+
+impl Destination {
+    pub fn new(
+        stream: Stream,
+        address: Endpoint,
+        listener: Endpoint,
+        capacity: usize,
+        slow_consumer: SlowConsumerPolicy,
+        unidentified: bool,
+        handshake: Option<Handshake>,
+    ) -> Destination {
+        Destination {
+            stream,
+            address,
+            listener,
+            identity: None,
+            unidentified,
+            handshake,
+            decoder: Decoder::new(),
+            trusted: false,
+            queue: VecDeque::new(),
+            capacity,
+            written: 0,
+            slow_consumer,
+            dropped: 0,
+            checksum: Arc::new(Rfc1071),
+            trailer: None,
+        }
+    }
+
+    /// Checks whether the destination can be sent packets: it's authenticated
+    /// and identified, if its listener requires it.
+    pub fn is_ready(&self) -> bool {
+        !self.unidentified && self.handshake.is_none()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.capacity
+    }
+
+    /// Adds the packet to the back of the queue, even if the queue is full.
+    pub fn push(&mut self, packet: Rc<[u8]>) {
+        self.queue.push_back(packet);
+    }
+
+    /// Removes the oldest queued packet that hasn't started being written.
+    ///
+    /// Returns false if there's no such packet.
+    pub fn drop_oldest(&mut self) -> bool {
+        // A partly written packet must be finished, or the stream is corrupted.
+        let oldest = if self.written > 0 { 1 } else { 0 };
+        self.queue.remove(oldest).is_some()
+    }
+
+    /// Reads anything the destination sent. Destinations only send packets to
+    /// authenticate, which are kept for `decode`. Anything else is discarded,
+    /// so this mostly detects disconnection.
+    ///
+    /// Returns false if the destination disconnected.
+    pub fn read_incoming(&mut self) -> Result<bool, Error> {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(bytes_read) => {
+                    // A destination can't make the proxy buffer more than one packet.
+                    if self.handshake.is_some() && self.decoder.buffered() < MAX_PACKET_LEN {
+                        self.decoder.feed(&buffer[..bytes_read]);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes the next complete packet the destination sent to authenticate.
+    pub fn decode(&mut self) -> Result<Option<Packet>, CtmpError> {
+        self.decoder.decode()
+    }
+
+    /// Bytes discarded by the last `decode` that returned an error.
+    pub fn rejected(&self) -> &[u8] {
+        self.decoder.rejected()
+    }
+
+    /// Writes queued packets until the queue is empty or the destination can't
+    /// take any more without blocking.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        while let Some(packet) = self.queue.front() {
+            match self.stream.write(&packet[self.written..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(bytes_written) => self.written += bytes_written,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            if self.written == packet.len() {
+                self.queue.pop_front();
+                self.written = 0;
+            }
+        }
+
+        // TLS streams buffer encrypted records that the socket couldn't take.
+        match self.stream.flush() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
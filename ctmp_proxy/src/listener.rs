@@ -0,0 +1,315 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use log::{info, warn};
+use mio::event;
+use mio::net::{TcpListener, UnixListener};
+use mio::{Interest, Registry, Token};
+use rustls::ServerConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::ListenerConfig;
+use crate::stream::{Endpoint, Stream};
+use crate::tls::{self, TlsStream};
+
+/// Number of pending connections queued for each TCP listener.
+const BACKLOG: i32 = 1024;
+
+/// Which kind of client a listener accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Source,
+    Destination,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Source => write!(f, "source"),
+            Role::Destination => write!(f, "destination"),
+        }
+    }
+}
+
+enum ListenerSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A bound listener. A Unix domain socket listener removes its socket file
+/// when dropped.
+pub struct Listener {
+    pub role: Role,
+    pub config: ListenerConfig,
+    socket: ListenerSocket,
+    /// Set if accepted connections are encrypted with TLS.
+    tls: Option<Arc<ServerConfig>>,
+    /// Number of connections closed because the client's address isn't allowed.
+    pub rejected: u64,
+}
+
+impl Listener {
+    /// Opens a listener on the endpoint in `config`.
+    ///
+    /// IPv6 listeners also accept IPv4 connections unless `config.ipv6_only`
+    /// is set. A socket file left behind by a process that no longer listens
+    /// on it is removed first. A socket file with `config.mode` set never has
+    /// looser permissions than the mode.
+    pub fn bind(role: Role, config: ListenerConfig) -> Result<Listener, Error> {
+        let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
+
+        let socket = match &config.address {
+            Endpoint::Tcp(address) => {
+                let socket = Socket::new(Domain::for_address(*address), Type::STREAM, Some(Protocol::TCP))?;
+                if address.is_ipv6() {
+                    socket.set_only_v6(config.ipv6_only)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*address).into())?;
+                socket.listen(BACKLOG)?;
+                ListenerSocket::Tcp(TcpListener::from_std(socket.into()))
+            }
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                match config.mode {
+                    Some(mode) => ListenerSocket::Unix(bind_with_mode(path, mode)?),
+                    None => ListenerSocket::Unix(UnixListener::bind(path)?),
+                }
+            }
+        };
+
+        let listener = Listener {
+            role,
+            config,
+            socket,
+            tls,
+            rejected: 0,
+        };
+
+        info!("Opened {} listener: {}", role, listener.config.address);
+
+        Ok(listener)
+    }
+
+    /// Accepts a pending connection.
+    ///
+    /// Unix domain socket clients are usually unnamed, so they're identified
+    /// by the listener's socket path instead.
+    pub fn accept(&self) -> Result<(Stream, Endpoint), Error> {
+        let (stream, address) = match &self.socket {
+            ListenerSocket::Tcp(listener) => {
+                let (stream, address) = listener.accept()?;
+                (Stream::Tcp(stream), Endpoint::Tcp(address))
+            }
+            ListenerSocket::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                (Stream::Unix(stream), self.config.address.clone())
+            }
+        };
+
+        match &self.tls {
+            Some(tls) => Ok((Stream::Tls(Box::new(TlsStream::new(stream, Arc::clone(tls))?)), address)),
+            None => Ok((stream, address)),
+        }
+    }
+
+    /// Checks whether a client connecting from `address` is allowed by the
+    /// listener's allow and deny lists.
+    pub fn allows(&self, address: &Endpoint) -> bool {
+        let Endpoint::Tcp(address) = address else {
+            return true;
+        };
+        let ip = address.ip().to_canonical();
+
+        let allowed = self.config.allow.is_empty() || self.config.allow.iter().any(|network| network.contains(&ip));
+        let denied = self.config.deny.iter().any(|network| network.contains(&ip));
+
+        allowed && !denied
+    }
+
+    /// Checks whether the listener is bound as described by `config`.
+    pub fn matches(&self, config: &ListenerConfig) -> bool {
+        self.config.address == config.address && self.config.ipv6_only == config.ipv6_only
+    }
+
+    /// Names of the settings that differ in `config` and only apply to clients
+    /// accepted after they change, as connected clients were checked against
+    /// the old ones.
+    pub fn changes_for_new_clients(&self, config: &ListenerConfig) -> Vec<&'static str> {
+        let changes = [
+            ("auth", self.config.auth != config.auth),
+            ("identities", self.config.identities != config.identities),
+            ("allow", self.config.allow != config.allow),
+            ("deny", self.config.deny != config.deny),
+        ];
+
+        changes.into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect()
+    }
+
+    /// Replaces the listener's config with one it `matches`, applying any new
+    /// socket file permissions. Certificates are read again, so they can be
+    /// renewed without closing the listener; connected clients aren't affected.
+    pub fn update(&mut self, config: ListenerConfig) -> Result<(), Error> {
+        let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
+        let mode_changed = self.config.mode != config.mode;
+        self.config = config;
+        self.tls = tls;
+
+        if mode_changed {
+            self.set_permissions()?;
+        }
+
+        Ok(())
+    }
+
+    fn set_permissions(&self) -> Result<(), Error> {
+        if let (Endpoint::Unix(path), Some(mode)) = (&self.config.address, self.config.mode) {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Endpoint::Unix(path) = &self.config.address {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl event::Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+        match &mut self.socket {
+            ListenerSocket::Tcp(listener) => listener.register(registry, token, interests),
+            ListenerSocket::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+        match &mut self.socket {
+            ListenerSocket::Tcp(listener) => listener.reregister(registry, token, interests),
+            ListenerSocket::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+        match &mut self.socket {
+            ListenerSocket::Tcp(listener) => listener.deregister(registry),
+            ListenerSocket::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+/// Removes the socket file at `path` if nothing is listening on it, which
+/// happens when a previous proxy didn't shut down cleanly.
+///
+/// Returns error if another process is listening on it, or the path isn't a
+/// socket file.
+fn remove_stale_socket(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(Error::other(format!("{} exists and isn't a socket", path.display())));
+    }
+
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(Error::other(format!("{} is in use by another process", path.display()))),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("Removing stale socket file: {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Binds a Unix domain socket at `path` with permissions `mode`.
+///
+/// The socket file is created with the umask's permissions, so it's bound in
+/// a private directory next to `path`, then moved into place once its
+/// permissions are set.
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener, Error> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::other(format!("Invalid socket path: {}", path.display())))?;
+    let mut private_name = OsString::from(".");
+    private_name.push(file_name);
+    private_name.push(format!(".{}", process::id()));
+    let private_dir = path.with_file_name(private_name);
+
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+    let private_path = private_dir.join(file_name);
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, Permissions::from_mode(mode))?;
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&private_dir);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(address: &str, allow: &[&str], deny: &[&str]) -> Listener {
+        let mut config = ListenerConfig::new(address.parse().unwrap());
+        config.allow = allow.iter().map(|network| network.parse().unwrap()).collect();
+        config.deny = deny.iter().map(|network| network.parse().unwrap()).collect();
+        Listener::bind(Role::Source, config).unwrap()
+    }
+
+    fn allows(listener: &Listener, address: &str) -> bool {
+        listener.allows(&Endpoint::Tcp(address.parse().unwrap()))
+    }
+
+    #[test]
+    fn empty_allow_list_allows_everyone() {
+        let listener = listener("127.0.0.1:0", &[], &[]);
+
+        assert!(allows(&listener, "127.0.0.1:5000"));
+        assert!(allows(&listener, "192.0.2.1:5000"));
+    }
+
+    #[test]
+    fn allow_list_allows_only_its_networks() {
+        let listener = listener("127.0.0.1:0", &["10.0.0.0/8"], &[]);
+
+        assert!(allows(&listener, "10.1.2.3:5000"));
+        assert!(!allows(&listener, "192.0.2.1:5000"));
+    }
+
+    #[test]
+    fn deny_list_overrides_allow_list() {
+        let listener = listener("127.0.0.1:0", &["10.0.0.0/8"], &["10.0.0.13/32"]);
+
+        assert!(allows(&listener, "10.0.0.12:5000"));
+        assert!(!allows(&listener, "10.0.0.13:5000"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_matches_ipv4_networks() {
+        let listener = listener("[::]:0", &["10.0.0.0/8"], &["10.0.0.13/32"]);
+
+        assert!(allows(&listener, "[::ffff:10.0.0.12]:5000"));
+        assert!(!allows(&listener, "[::ffff:10.0.0.13]:5000"));
+        assert!(!allows(&listener, "[::ffff:192.0.2.1]:5000"));
+    }
+}
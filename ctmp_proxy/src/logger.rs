@@ -0,0 +1,36 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Writes log records to stdout, or to stderr for warnings and errors.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{:<5} {}", record.level(), record.args()),
+            Level::Info | Level::Debug | Level::Trace => println!("{:<5} {}", record.level(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Installs the logger, only logging records at `level` or above.
+pub fn init(level: LevelFilter) {
+    let _ = log::set_logger(&LOGGER);
+    set_level(level);
+}
+
+/// Only logs records at `level` or above from now on.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
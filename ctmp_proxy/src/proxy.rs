@@ -0,0 +1,1375 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ctmp::{Checksum, CtmpError, Decoder, HmacTrailer, Packet};
+use log::{debug, error, info, warn};
+use mio::event::Event;
+use mio::{Events, Interest, Poll, Token};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook_mio::v1_0::Signals;
+
+use crate::auth::Handshake;
+use crate::config::{Args, ChecksumAlgorithm, Config, ListenerConfig, SourceConfig};
+use crate::destination::{Destination, SensitivePolicy, SlowConsumerPolicy};
+use crate::listener::{Listener, Role};
+use crate::logger;
+use crate::quarantine::Quarantine;
+use crate::source::{Source, SourcePolicy};
+use crate::stream::{Endpoint, Stream};
+
+const SIGNALS: Token = Token(0);
+
+/// First token handed out to a listener or client.
+const FIRST_TOKEN: usize = 1;
+
+/// Maximum number of readiness events handled per wake up.
+const EVENTS_CAPACITY: usize = 128;
+
+/// Forwards packets from every source client to every destination client.
+///
+/// The proxy sleeps until one of the listeners or sources is ready, so an
+/// idle proxy doesn't use any CPU. Ready sources are read round-robin, one
+/// read each per turn, so a chatty source can't starve the others.
+///
+/// On `SIGHUP` the config is reloaded and applied without disconnecting any
+/// clients. On `SIGINT` or `SIGTERM` the proxy stops, and dropping it removes
+/// the socket files of its Unix domain socket listeners.
+pub struct Proxy {
+    poll: Poll,
+    signals: Signals,
+    listeners: HashMap<Token, Listener>,
+    /// Command line options, reapplied over the config file on every reload.
+    args: Args,
+    config: Config,
+    sources: HashMap<Token, Source>,
+    /// Sources that may have more data to read, in the order they'll be read.
+    ready_sources: VecDeque<Token>,
+    /// Standby sources, in the order they'll take over from the active source.
+    standby_sources: VecDeque<Token>,
+    destinations: HashMap<Token, Destination>,
+    /// Set if rejected packets are written to a quarantine file.
+    quarantine: Option<Quarantine>,
+    /// Checksum algorithms in use, shared by every client using the same one,
+    /// so packets only have their checksum recalculated for clients that use
+    /// another algorithm.
+    checksums: HashMap<ChecksumAlgorithm, Arc<dyn Checksum>>,
+    /// HMAC trailer keys in use, shared the same way as `checksums`.
+    trailers: HashMap<String, Arc<HmacTrailer>>,
+    next_token: usize,
+}
+
+impl Proxy {
+    pub fn new(args: Args, config: Config) -> Result<Proxy, Error> {
+        let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+
+        let quarantine = config.quarantine.as_deref().map(Quarantine::open).transpose()?;
+
+        let poll = Poll::new()?;
+        poll.registry().register(&mut signals, SIGNALS, Interest::READABLE)?;
+
+        let mut proxy = Proxy {
+            poll,
+            signals,
+            listeners: HashMap::new(),
+            args,
+            config,
+            sources: HashMap::new(),
+            ready_sources: VecDeque::new(),
+            standby_sources: VecDeque::new(),
+            destinations: HashMap::new(),
+            quarantine,
+            checksums: HashMap::new(),
+            trailers: HashMap::new(),
+            next_token: FIRST_TOKEN,
+        };
+
+        for listener in proxy.config.source.listen.clone() {
+            proxy.open_listener(Role::Source, listener)?;
+        }
+        for listener in proxy.config.destination.listen.clone() {
+            proxy.open_listener(Role::Destination, listener)?;
+        }
+
+        Ok(proxy)
+    }
+
+    /// Handles events until `SIGINT` or `SIGTERM` is received, or polling fails.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        while !self.turn(&mut events, None)? {}
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for events, or forever if it's None, handles them
+    /// and reads the ready sources.
+    ///
+    /// Returns whether the proxy should stop.
+    fn turn(&mut self, events: &mut Events, timeout: Option<Duration>) -> Result<bool, Error> {
+        // Don't sleep while there are sources left to read.
+        let timeout = if self.ready_sources.is_empty() || self.is_backpressured() {
+            timeout
+        } else {
+            Some(Duration::ZERO)
+        };
+
+        if let Err(e) = self.poll.poll(events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                SIGNALS => {
+                    if self.handle_signals() {
+                        return Ok(true);
+                    }
+                }
+                token if self.listeners.contains_key(&token) => self.accept(token),
+                token if self.destinations.contains_key(&token) => self.handle_destination(token, event),
+                token => self.handle_source(token, event),
+            }
+        }
+
+        self.read_ready_sources();
+
+        Ok(false)
+    }
+
+    /// Accepts every pending connection on the listener.
+    fn accept(&mut self, token: Token) {
+        loop {
+            let Some(listener) = self.listeners.get_mut(&token) else {
+                return;
+            };
+            let role = listener.role;
+            let listener_address = listener.config.address.clone();
+
+            let (stream, address) = match listener.accept() {
+                Ok(connection) => connection,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Failed to accept {} connection on {}: {}", role, listener_address, e);
+                    return;
+                }
+            };
+
+            // Dropping the stream closes the connection.
+            if !listener.allows(&address) {
+                listener.rejected += 1;
+                warn!(
+                    "Rejected {} connection from {}: address not allowed on {} ({} rejected)",
+                    role, address, listener_address, listener.rejected
+                );
+                continue;
+            }
+
+            info!("New {} connection: {}", role, address);
+
+            let handshake = match listener.config.auth.as_ref().map(Handshake::new).transpose() {
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    error!("Failed to authenticate {} {}: {}", role, address, e);
+                    continue;
+                }
+            };
+
+            match role {
+                Role::Source => self.add_source(stream, address, listener_address, handshake),
+                Role::Destination => self.add_destination(stream, address, listener_address, handshake),
+            }
+        }
+    }
+
+    fn add_source(&mut self, stream: Stream, address: Endpoint, listener: Endpoint, mut handshake: Option<Handshake>) {
+        let challenge = handshake.as_mut().and_then(Handshake::take_challenge);
+        let checksum = shared_checksum(&mut self.checksums, &self.config.source.listen, &listener);
+        let trailer = shared_trailer(&mut self.trailers, &self.config.source.listen, &listener);
+        let decoder = new_decoder(&self.config.source, &listener)
+            .with_checksum(checksum)
+            .with_trailer(trailer);
+        let mut source = Source::new(stream, address.clone(), listener, decoder, handshake);
+
+        if let Some(challenge) = challenge
+            && let Err(e) = source.stream.write_all(&challenge.encode())
+        {
+            warn!("Failed to send challenge to source {}: {}", address, e);
+            return;
+        }
+
+        // TLS records, like the challenge, may have to wait until the socket is writable.
+        source.writable = source.stream.wants_write();
+        let interest = if source.writable {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+
+        let token = self.next_token();
+        if let Err(e) = self.poll.registry().register(&mut source.stream, token, interest) {
+            error!("Failed to register source {}: {}", address, e);
+            return;
+        }
+
+        let authenticated = source.handshake.is_none();
+        self.sources.insert(token, source);
+
+        // Sources that must authenticate are admitted once they have.
+        if authenticated {
+            self.admit_source(token);
+        }
+    }
+
+    /// Applies the source policy to a new source, now that it's authenticated.
+    ///
+    /// Returns whether the source is active, rather than closed or in standby.
+    fn admit_source(&mut self, token: Token) -> bool {
+        let Some(source) = self.sources.get(&token) else {
+            return false;
+        };
+        let address = source.address.clone();
+
+        let others_active = self
+            .sources
+            .iter()
+            .any(|(other, source)| *other != token && source.is_active());
+
+        if others_active {
+            match self.config.source.policy {
+                SourcePolicy::Concurrent => {}
+                SourcePolicy::Reject => {
+                    warn!("Rejected source {}: another source is connected", address);
+                    self.close_source(token);
+                    return false;
+                }
+                SourcePolicy::Preempt => self.preempt_sources(token),
+                SourcePolicy::Standby => {
+                    info!("Holding source {} in standby", address);
+                    if let Some(source) = self.sources.get_mut(&token) {
+                        source.standby = true;
+                    }
+                    self.standby_sources.push_back(token);
+
+                    // It may have hung up while authenticating.
+                    self.queue_source(token);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    fn has_active_source(&self) -> bool {
+        self.sources.values().any(Source::is_active)
+    }
+
+    /// Closes every other active source in favour of the new source `token`.
+    fn preempt_sources(&mut self, token: Token) {
+        let Some(address) = self.sources.get(&token).map(|source| source.address.clone()) else {
+            return;
+        };
+
+        let active: Vec<Token> = self
+            .sources
+            .iter()
+            .filter(|(other, source)| **other != token && source.is_active())
+            .map(|(other, _)| *other)
+            .collect();
+
+        for token in active {
+            if let Some(source) = self.sources.get(&token) {
+                warn!("Source {} preempted by {}", source.address, address);
+            }
+            self.close_source(token);
+        }
+    }
+
+    fn add_destination(
+        &mut self,
+        mut stream: Stream,
+        address: Endpoint,
+        listener: Endpoint,
+        mut handshake: Option<Handshake>,
+    ) {
+        let token = self.next_token();
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        if let Err(e) = self.poll.registry().register(&mut stream, token, interest) {
+            error!("Failed to register destination {}: {}", address, e);
+            return;
+        }
+
+        let capacity = self.config.destination.queue_capacity;
+        let slow_consumer = self.slow_consumer_for(&listener);
+        let unidentified = self
+            .destination_listener(&listener)
+            .is_some_and(|config| config.verifies_clients());
+        let challenge = handshake.as_mut().and_then(Handshake::take_challenge);
+        let mut destination =
+            Destination::new(stream, address, listener, capacity, slow_consumer, unidentified, handshake);
+
+        // Written once the destination is writable.
+        if let Some(challenge) = challenge {
+            destination.push(Rc::from(challenge.encode()));
+        }
+
+        destination.trusted = self.is_trusted(&destination);
+        destination.checksum =
+            shared_checksum(&mut self.checksums, &self.config.destination.listen, &destination.listener);
+        destination.trailer =
+            shared_trailer(&mut self.trailers, &self.config.destination.listen, &destination.listener);
+        self.destinations.insert(token, destination);
+    }
+
+    /// Config of the destination listener at `listener`. None if the listener
+    /// has been removed from the config.
+    fn destination_listener(&self, listener: &Endpoint) -> Option<&ListenerConfig> {
+        self.config.destination.listen.iter().find(|config| config.address == *listener)
+    }
+
+    /// Slow consumer policy for destinations accepted by the listener at `listener`.
+    fn slow_consumer_for(&self, listener: &Endpoint) -> SlowConsumerPolicy {
+        self.destination_listener(listener)
+            .and_then(|config| config.slow_consumer)
+            .unwrap_or(self.config.slow_consumer)
+    }
+
+    /// Checks whether the destination is trusted with 'sensitive' packets,
+    /// because of its listener, its address or its identity.
+    fn is_trusted(&self, destination: &Destination) -> bool {
+        let config = &self.config.destination;
+
+        let by_listener = self
+            .destination_listener(&destination.listener)
+            .is_some_and(|listener| listener.trusted);
+
+        let by_address = match &destination.address {
+            Endpoint::Tcp(address) => {
+                let ip = address.ip().to_canonical();
+                config.trusted_networks.iter().any(|network| network.contains(&ip))
+            }
+            Endpoint::Unix(_) => false,
+        };
+
+        let by_identity = destination
+            .identity
+            .as_ref()
+            .is_some_and(|identity| config.trusted_identities.iter().any(|name| identity.matches(name)));
+
+        by_listener || by_address || by_identity
+    }
+
+    /// Checks the packet a destination sent to authenticate, if it hasn't yet.
+    ///
+    /// Returns false if the destination failed to authenticate and was closed.
+    fn authenticate_destination(&mut self, token: Token) -> bool {
+        let Some(destination) = self.destinations.get_mut(&token) else {
+            return false;
+        };
+
+        while destination.handshake.is_some() {
+            let packet = match destination.decode() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    let address = &destination.address;
+                    reject_packet(&mut self.quarantine, Role::Destination, address, &e, destination.rejected());
+                    continue;
+                }
+            };
+
+            if !destination.handshake.as_ref().is_some_and(|handshake| handshake.verify(&packet)) {
+                warn!("Failed to authenticate destination {}", destination.address);
+                self.close_destination(token);
+                return false;
+            }
+
+            info!("Destination {} authenticated", destination.address);
+            destination.handshake = None;
+        }
+
+        true
+    }
+
+    /// Records the destination's identity once its TLS handshake is complete,
+    /// closing it if the identity isn't one its listener allows, or can't be
+    /// read from its certificate.
+    ///
+    /// Returns false if the destination was closed.
+    fn identify_destination(&mut self, token: Token) -> bool {
+        let Some(destination) = self.destinations.get(&token) else {
+            return false;
+        };
+
+        if !destination.unidentified {
+            return true;
+        }
+
+        if destination.stream.is_handshaking() {
+            return true;
+        }
+
+        // The certificate was verified, but it may still not be one we can read names from.
+        let Some(identity) = destination.stream.peer_identity() else {
+            warn!("Rejected destination {}: its certificate has no usable identity", destination.address);
+            self.close_destination(token);
+            return false;
+        };
+
+        let allowed = self
+            .destination_listener(&destination.listener)
+            .is_none_or(|config| {
+                config.identities.is_empty() || config.identities.iter().any(|name| identity.matches(name))
+            });
+
+        if !allowed {
+            warn!("Rejected destination {}: {} isn't allowed", destination.address, identity);
+            self.close_destination(token);
+            return false;
+        }
+
+        info!("Destination {} identified as {}", destination.address, identity);
+
+        if let Some(destination) = self.destinations.get_mut(&token) {
+            destination.identity = Some(identity);
+            destination.unidentified = false;
+        }
+
+        // The identity may make the destination trusted.
+        let trusted = self.destinations.get(&token).is_some_and(|destination| self.is_trusted(destination));
+        if let Some(destination) = self.destinations.get_mut(&token) {
+            destination.trusted = trusted;
+        }
+
+        true
+    }
+
+    /// Closes the destination if it disconnected or failed, otherwise writes
+    /// its queued packets now that it can take more.
+    fn handle_destination(&mut self, token: Token, event: &Event) {
+        let Some(destination) = self.destinations.get_mut(&token) else {
+            return;
+        };
+
+        if event.is_readable() {
+            match destination.read_incoming() {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.close_destination(token);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to read from destination {}: {}", destination.address, e);
+                    self.close_destination(token);
+                    return;
+                }
+            }
+        }
+
+        // Reading drives the TLS handshake, after which the client certificate is known.
+        if !self.authenticate_destination(token) || !self.identify_destination(token) {
+            return;
+        }
+
+        let Some(destination) = self.destinations.get_mut(&token) else {
+            return;
+        };
+
+        if let Err(e) = destination.flush() {
+            warn!("Failed to send data to {}: {}", destination.address, e);
+            self.close_destination(token);
+        }
+    }
+
+    /// Notes whether the source hung up, then queues it to be read.
+    fn handle_source(&mut self, token: Token, event: &Event) {
+        let Some(source) = self.sources.get_mut(&token) else {
+            return;
+        };
+
+        // Data may be buffered ahead of the end of the stream, so a standby
+        // source can't tell it's closed by peeking.
+        if event.is_read_closed() || event.is_error() {
+            source.hung_up = true;
+        }
+
+        self.queue_source(token);
+    }
+
+    /// Adds the source to the back of the ready queue, unless it's already
+    /// queued. Standby sources aren't read; one that hung up is closed, so
+    /// it's never promoted.
+    fn queue_source(&mut self, token: Token) {
+        let Some(source) = self.sources.get_mut(&token) else {
+            return;
+        };
+
+        if source.standby {
+            if source.hung_up {
+                info!("Standby source disconnected: {}", source.address);
+                self.close_source(token);
+            }
+            return;
+        }
+
+        if !source.queued {
+            source.queued = true;
+            self.ready_sources.push_back(token);
+        }
+    }
+
+    /// Checks whether a destination with the backpressure policy is full, in
+    /// which case sources aren't read until it catches up.
+    fn is_backpressured(&self) -> bool {
+        self.destinations
+            .values()
+            .any(|destination| destination.slow_consumer == SlowConsumerPolicy::Backpressure && destination.is_full())
+    }
+
+    /// Gives every ready source one read. Sources that may have more data are
+    /// queued again for the next turn.
+    fn read_ready_sources(&mut self) {
+        if self.is_backpressured() {
+            return;
+        }
+
+        for _ in 0..self.ready_sources.len() {
+            let Some(token) = self.ready_sources.pop_front() else {
+                return;
+            };
+
+            if self.read_from_source(token) {
+                self.ready_sources.push_back(token);
+            } else if let Some(source) = self.sources.get_mut(&token) {
+                source.queued = false;
+            }
+        }
+    }
+
+    /// Performs a single read from the source and forwards every complete
+    /// packet, including ones left from earlier reads. Any partial packet is
+    /// kept for the next read.
+    ///
+    /// Returns whether the source may have more data to read.
+    fn read_from_source(&mut self, token: Token) -> bool {
+        let Some(source) = self.sources.get_mut(&token) else {
+            return false;
+        };
+
+        let result = source.read();
+
+        // Reading sends pending TLS records, so writable events are only
+        // needed while some are left.
+        if source.writable != source.stream.wants_write() {
+            source.writable = !source.writable;
+            let interest = if source.writable {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+
+            if let Err(e) = self.poll.registry().reregister(&mut source.stream, token, interest) {
+                error!("Failed to reregister source {}: {}", source.address, e);
+                self.close_source(token);
+                return false;
+            }
+        }
+
+        // Packets can be buffered without new data, e.g. ones that arrived
+        // with the authentication packet while the source was held in standby.
+        match result {
+            Ok(bytes_read) => debug!("{} bytes read from source {}", bytes_read, source.address),
+            Err(CtmpError::PeerClosed) => {
+                self.forward_packets(token);
+                if let Some(source) = self.sources.get_mut(&token) {
+                    if let Err(e) = source.decoder_mut().finish() {
+                        reject_packet(&mut self.quarantine, Role::Source, &source.address, &e, source.rejected());
+                    }
+                    info!("Source disconnected: {}", source.address);
+                }
+                self.close_source(token);
+                return false;
+            }
+            Err(e) if e.is_would_block() => {
+                self.forward_packets(token);
+                return false;
+            }
+            Err(e) => {
+                warn!("Failed to read from source {}: {}", source.address, e);
+                self.close_source(token);
+                return false;
+            }
+        }
+
+        self.forward_packets(token)
+    }
+
+    /// Authenticates the source if it hasn't yet, then forwards every complete
+    /// packet buffered from it.
+    ///
+    /// Returns whether the source should still be read, i.e. it's active or
+    /// still authenticating.
+    fn forward_packets(&mut self, token: Token) -> bool {
+        if !self.authenticate_source(token) {
+            // Keep reading a source that's still authenticating.
+            return self.sources.get(&token).is_some_and(|source| source.handshake.is_some());
+        }
+
+        let Some(source) = self.sources.get_mut(&token) else {
+            return false;
+        };
+
+        let mut dead_destinations = Vec::new();
+
+        loop {
+            let packet = source.decode();
+
+            if let Some(e) = source.nonconformity() {
+                // Only the first is a warning, so a drifting producer doesn't flood the log.
+                if source.nonconforming == 1 {
+                    warn!("Nonconforming packet from source {}: {}, forwarding anyway", source.address, e);
+                } else {
+                    debug!("Nonconforming packet from source {}: {}", source.address, e);
+                }
+            }
+
+            match packet {
+                Ok(Some(packet)) if source.reserves_control && packet.header.is_control() => {
+                    warn!("Unexpected control packet from source {}", source.address)
+                }
+                Ok(Some(packet)) => {
+                    if let Some(checksum) = source.repaired() {
+                        warn!(
+                            "Repaired checksum of packet from source {}: {} replaced with {}",
+                            source.address, checksum, packet.header.checksum
+                        );
+                    }
+
+                    broadcast_to_destinations(
+                        &mut self.destinations,
+                        &packet,
+                        source.checksum(),
+                        source.checks_trailers(),
+                        self.config.destination.untrusted_sensitive,
+                        &mut dead_destinations,
+                    )
+                }
+                Ok(None) => break,
+                Err(e) => reject_packet(&mut self.quarantine, Role::Source, &source.address, &e, source.rejected()),
+            }
+        }
+
+        for token in dead_destinations {
+            self.close_destination(token);
+        }
+
+        true
+    }
+
+    /// Checks the first packet from a source that hasn't authenticated yet,
+    /// then admits it as a new source.
+    ///
+    /// Returns whether the source is authenticated and active, so its packets
+    /// can be forwarded.
+    fn authenticate_source(&mut self, token: Token) -> bool {
+        let Some(source) = self.sources.get_mut(&token) else {
+            return false;
+        };
+
+        if source.handshake.is_none() {
+            return true;
+        }
+
+        let packet = loop {
+            match source.decode() {
+                Ok(Some(packet)) => break packet,
+                Ok(None) => return false,
+                Err(e) => reject_packet(&mut self.quarantine, Role::Source, &source.address, &e, source.rejected()),
+            }
+        };
+
+        if !source.handshake.as_ref().is_some_and(|handshake| handshake.verify(&packet)) {
+            warn!("Failed to authenticate source {}", source.address);
+            self.close_source(token);
+            return false;
+        }
+
+        info!("Source {} authenticated", source.address);
+        source.handshake = None;
+
+        self.admit_source(token)
+    }
+
+    fn close_destination(&mut self, token: Token) {
+        if let Some(mut destination) = self.destinations.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut destination.stream);
+            info!("Destination disconnected: {} ({} packets dropped)", destination.address, destination.dropped);
+        }
+    }
+
+    /// Closes the source. If it was an active source, the longest waiting
+    /// standby source takes over.
+    fn close_source(&mut self, token: Token) {
+        let Some(mut source) = self.sources.remove(&token) else {
+            return;
+        };
+
+        let _ = self.poll.registry().deregister(&mut source.stream);
+
+        if source.nonconforming > 0 {
+            warn!("Source {} sent {} nonconforming packets", source.address, source.nonconforming);
+        }
+
+        if source.standby {
+            self.standby_sources.retain(|standby| *standby != token);
+        } else if !self.has_active_source() {
+            self.promote_standby_source();
+        }
+    }
+
+    fn promote_standby_source(&mut self) {
+        let Some(token) = self.standby_sources.pop_front() else {
+            return;
+        };
+
+        if let Some(source) = self.sources.get_mut(&token) {
+            info!("Standby source {} is now active", source.address);
+            source.standby = false;
+        }
+
+        // Data may have arrived while in standby, so read without waiting for an event.
+        self.queue_source(token);
+    }
+
+    /// Reloads the config if `SIGHUP` was received.
+    ///
+    /// Returns whether the proxy should stop.
+    fn handle_signals(&mut self) -> bool {
+        let mut reload = false;
+        let mut stop = false;
+
+        for signal in self.signals.pending() {
+            match signal {
+                SIGHUP => reload = true,
+                SIGINT | SIGTERM => stop = true,
+                _ => {}
+            }
+        }
+
+        if stop {
+            info!("Shutting down");
+            return true;
+        }
+
+        if reload {
+            self.reload();
+        }
+
+        false
+    }
+
+    /// Re-reads the config and applies it without disconnecting any clients.
+    /// If the new config is invalid, the current config is kept. Changes that
+    /// only apply to new clients are logged.
+    fn reload(&mut self) {
+        info!("Reloading config");
+
+        let config = match Config::load(&self.args) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to reload config, keeping the current config: {}", e);
+                return;
+            }
+        };
+
+        logger::set_level(config.log_level);
+
+        // Reopened even if unchanged, so the file can be rotated.
+        self.reload_quarantine(config.quarantine.as_deref());
+
+        let policy_changed = self.config.source.policy != config.source.policy;
+        self.config = config;
+        self.reload_listeners();
+
+        // Keys may have changed, so checksums and trailers are built again.
+        self.checksums.clear();
+        self.trailers.clear();
+
+        // Connected clients pick up the new config too.
+        for source in self.sources.values_mut() {
+            source.decoder_mut().set_read_len(self.config.source.read_buffer_size);
+            let checksum_policy = self.config.source.checksum_for(&source.listener);
+            source.decoder_mut().set_checksum_policy(checksum_policy);
+            let checksum = shared_checksum(&mut self.checksums, &self.config.source.listen, &source.listener);
+            source.decoder_mut().set_checksum(checksum);
+            let trailer = shared_trailer(&mut self.trailers, &self.config.source.listen, &source.listener);
+            source.decoder_mut().set_trailer(trailer);
+            source.decoder_mut().set_bad_checksum_policy(self.config.source.bad_checksum);
+            source.decoder_mut().set_header_validation(self.config.source.header_validation);
+            source.decoder_mut().set_resync_policy(self.config.source.resync);
+            source.decoder_mut().set_max_length(self.config.source.max_length);
+            source.decoder_mut().set_resync_max_length(self.config.source.resync_max_length);
+        }
+
+        let updates: Vec<(Token, SlowConsumerPolicy, bool)> = self
+            .destinations
+            .iter()
+            .map(|(token, destination)| {
+                (*token, self.slow_consumer_for(&destination.listener), self.is_trusted(destination))
+            })
+            .collect();
+
+        for (token, slow_consumer, trusted) in updates {
+            if let Some(destination) = self.destinations.get_mut(&token) {
+                destination.capacity = self.config.destination.queue_capacity;
+                destination.slow_consumer = slow_consumer;
+                destination.trusted = trusted;
+                destination.checksum =
+                    shared_checksum(&mut self.checksums, &self.config.destination.listen, &destination.listener);
+                destination.trailer =
+                    shared_trailer(&mut self.trailers, &self.config.destination.listen, &destination.listener);
+            }
+        }
+
+        // Standby sources are only held while the policy asks for it.
+        if self.config.source.policy == SourcePolicy::Concurrent {
+            while !self.standby_sources.is_empty() {
+                self.promote_standby_source();
+            }
+        } else if policy_changed {
+            // Only new sources are rejected or held, the active ones aren't closed.
+            let active = self.sources.values().filter(|source| source.is_active()).count();
+            if active > 1 {
+                warn!("Changed source policy only applies to new sources, {} sources stay active", active);
+            }
+        }
+
+        info!("Config reloaded");
+    }
+
+    /// Closes listeners that are no longer in the config and opens the new
+    /// ones. Clients accepted by a closed listener stay connected. Listeners
+    /// that fail to open are retried on the next reload.
+    fn reload_listeners(&mut self) {
+        let configured: Vec<(Role, ListenerConfig)> = self
+            .config
+            .source
+            .listen
+            .iter()
+            .map(|config| (Role::Source, config.clone()))
+            .chain(self.config.destination.listen.iter().map(|config| (Role::Destination, config.clone())))
+            .collect();
+
+        let removed: Vec<Token> = self
+            .listeners
+            .iter()
+            .filter(|(_, listener)| {
+                !configured
+                    .iter()
+                    .any(|(role, config)| listener.role == *role && listener.matches(config))
+            })
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in removed {
+            if let Some(mut listener) = self.listeners.remove(&token) {
+                let _ = self.poll.registry().deregister(&mut listener);
+                info!(
+                    "Closed {} listener: {} ({} rejected)",
+                    listener.role, listener.config.address, listener.rejected
+                );
+            }
+        }
+
+        for (role, config) in configured {
+            let existing = self
+                .listeners
+                .values_mut()
+                .find(|listener| listener.role == role && listener.matches(&config));
+
+            match existing {
+                Some(listener) => {
+                    let changes = listener.changes_for_new_clients(&config);
+                    if !changes.is_empty() {
+                        warn!(
+                            "Changed {} of {} listener {} only applies to new clients",
+                            changes.join(", "),
+                            listener.role,
+                            listener.config.address
+                        );
+                    }
+
+                    if let Err(e) = listener.update(config) {
+                        error!("Failed to update {} listener {}: {}", listener.role, listener.config.address, e);
+                    }
+                }
+                None => {
+                    if let Err(e) = self.open_listener(role, config) {
+                        error!("{}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reopens the quarantine file, or stops quarantining if there's none. If
+    /// the file fails to open, the current one is kept.
+    fn reload_quarantine(&mut self, path: Option<&Path>) {
+        match path.map(Quarantine::open).transpose() {
+            Ok(quarantine) => self.quarantine = quarantine,
+            Err(e) => error!("{}, keeping the current quarantine file", e),
+        }
+    }
+
+    fn open_listener(&mut self, role: Role, config: ListenerConfig) -> Result<(), Error> {
+        let address = config.address.clone();
+        let token = self.next_token();
+
+        Listener::bind(role, config)
+            .and_then(|mut listener| {
+                self.poll.registry().register(&mut listener, token, Interest::READABLE)?;
+                self.listeners.insert(token, listener);
+                Ok(())
+            })
+            .map_err(|e| Error::other(format!("Failed to open {} listener on {}: {}", role, address, e)))
+    }
+
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+}
+
+/// Logs why a packet from the client at `address` was rejected, and appends
+/// the `rejected` bytes to the quarantine file, if any.
+fn reject_packet(quarantine: &mut Option<Quarantine>, role: Role, address: &Endpoint, e: &CtmpError, rejected: &[u8]) {
+    if let Some(quarantine) = quarantine
+        && let Err(e) = quarantine.write(role, address, e, rejected)
+    {
+        error!("Failed to write to quarantine file {}: {}", quarantine.path.display(), e);
+    }
+
+    match e {
+        // The start of the next packet is unknown, so everything buffered is lost.
+        CtmpError::BadMagic(_) | CtmpError::Oversize(_) => {
+            warn!("Invalid packet from {} {}: {}, discarded buffered data", role, address, e)
+        }
+        CtmpError::Resynced { .. } => warn!("Corrupted stream from {} {}: {}", role, address, e),
+        // Only the bad packet is lost.
+        _ => warn!("Invalid packet from {} {}: {}", role, address, e),
+    }
+}
+
+/// Decoder for a source accepted by the listener at `listener`.
+fn new_decoder(config: &SourceConfig, listener: &Endpoint) -> Decoder {
+    Decoder::new()
+        .with_read_len(config.read_buffer_size)
+        .with_checksum_policy(config.checksum_for(listener))
+        .with_bad_checksum_policy(config.bad_checksum)
+        .with_header_validation(config.header_validation)
+        .with_resync_policy(config.resync)
+        .with_max_length(config.max_length)
+        .with_resync_max_length(config.resync_max_length)
+}
+
+/// Checksum algorithm of the listener at `listener`, one of `listeners`,
+/// shared with other clients through `checksums`.
+fn shared_checksum(
+    checksums: &mut HashMap<ChecksumAlgorithm, Arc<dyn Checksum>>,
+    listeners: &[ListenerConfig],
+    listener: &Endpoint,
+) -> Arc<dyn Checksum> {
+    let algorithm = listeners
+        .iter()
+        .find(|config| config.address == *listener)
+        .map(|config| config.checksum_algorithm.clone())
+        .unwrap_or_default();
+
+    Arc::clone(checksums.entry(algorithm).or_insert_with_key(ChecksumAlgorithm::build))
+}
+
+/// HMAC trailer key of the listener at `listener`, one of `listeners`, if it
+/// has one, shared with other clients through `trailers`.
+fn shared_trailer(
+    trailers: &mut HashMap<String, Arc<HmacTrailer>>,
+    listeners: &[ListenerConfig],
+    listener: &Endpoint,
+) -> Option<Arc<HmacTrailer>> {
+    let key = listeners
+        .iter()
+        .find(|config| config.address == *listener)
+        .and_then(|config| config.sensitive_hmac.as_ref())?;
+
+    let trailer = trailers
+        .entry(key.clone())
+        .or_insert_with_key(|key| Arc::new(HmacTrailer::new(key.as_bytes())));
+    Some(Arc::clone(trailer))
+}
+
+/// Queues the `packet`, whose checksum was calculated with `checksum`, for
+/// every destination and writes as much as each destination can take without
+/// blocking. If the packet is 'sensitive', untrusted destinations get what
+/// `untrusted_sensitive` says instead, and if `trailer_checked`, destinations
+/// with their own trailer key get it re-signed.
+///
+/// Destinations that failed, or are too slow and should be disconnected, are
+/// added to `dead_destinations`.
+fn broadcast_to_destinations(
+    destinations: &mut HashMap<Token, Destination>,
+    packet: &Packet,
+    checksum: &Arc<dyn Checksum>,
+    trailer_checked: bool,
+    untrusted_sensitive: SensitivePolicy,
+    dead_destinations: &mut Vec<Token>,
+) {
+    let signed = trailer_checked && packet.header.is_sensitive();
+    let mut encodings = Encodings::new(packet, checksum, signed);
+
+    for (token, destination) in destinations.iter_mut() {
+        if dead_destinations.contains(token) || !destination.is_ready() {
+            continue;
+        }
+
+        let redacted = if destination.trusted || !packet.header.is_sensitive() {
+            false
+        } else {
+            match untrusted_sensitive {
+                SensitivePolicy::Forward => false,
+                SensitivePolicy::Withhold => {
+                    debug!("Withheld sensitive packet from untrusted destination {}", destination.address);
+                    continue;
+                }
+                SensitivePolicy::Redact => true,
+            }
+        };
+        let bytes = encodings.get(&destination.checksum, destination.trailer.as_ref(), redacted);
+
+        if destination.is_full() {
+            match destination.slow_consumer {
+                SlowConsumerPolicy::DropOldest | SlowConsumerPolicy::DropNewest => {
+                    destination.dropped += 1;
+
+                    // If only a partly written packet is queued, drop the new packet instead.
+                    if destination.slow_consumer == SlowConsumerPolicy::DropNewest || !destination.drop_oldest() {
+                        warn!("Queue full, dropped newest packet for {} ({} dropped)", destination.address, destination.dropped);
+                        continue;
+                    }
+
+                    warn!("Queue full, dropped oldest packet for {} ({} dropped)", destination.address, destination.dropped);
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    warn!("Queue full, disconnecting {}", destination.address);
+                    dead_destinations.push(*token);
+                    continue;
+                }
+                // Sources stop being read until the destination catches up.
+                SlowConsumerPolicy::Backpressure => {}
+            }
+        }
+
+        destination.push(Rc::clone(&bytes));
+
+        debug!("Sending {} bytes to {}", bytes.len(), destination.address);
+
+        if let Err(e) = destination.flush() {
+            warn!("Failed to send data to {}: {}", destination.address, e);
+            dead_destinations.push(*token);
+        }
+    }
+}
+
+/// The encodings of a packet being broadcast. Each is made once, when the
+/// first destination needs it, and shared with the other destinations.
+struct Encodings<'a> {
+    packet: &'a Packet,
+    /// Algorithm the packet's checksum was calculated with.
+    checksum: &'a Arc<dyn Checksum>,
+    /// Whether the packet's HMAC trailer was checked when it was read.
+    signed: bool,
+    /// Whether the packet's checksum is valid, once checked.
+    valid: Option<bool>,
+    encoded: Vec<Encoding>,
+}
+
+struct Encoding {
+    checksum: Arc<dyn Checksum>,
+    trailer: Option<Arc<HmacTrailer>>,
+    redacted: bool,
+    bytes: Rc<[u8]>,
+}
+
+impl<'a> Encodings<'a> {
+    fn new(packet: &'a Packet, checksum: &'a Arc<dyn Checksum>, signed: bool) -> Encodings<'a> {
+        Encodings {
+            packet,
+            checksum,
+            signed,
+            valid: None,
+            encoded: Vec::new(),
+        }
+    }
+
+    /// The packet for a destination using `checksum` and `trailer`, with its
+    /// data zeroed if `redacted`.
+    ///
+    /// The checksum is only recalculated for another algorithm if it's valid,
+    /// and the trailer is only re-signed if it was checked, so a corrupted or
+    /// forged packet isn't passed on as an intact one.
+    fn get(&mut self, checksum: &Arc<dyn Checksum>, trailer: Option<&Arc<HmacTrailer>>, redacted: bool) -> Rc<[u8]> {
+        let trailer = trailer.filter(|_| self.signed && !redacted);
+
+        if let Some(encoding) = self.encoded.iter().find(|encoding| {
+            Arc::ptr_eq(&encoding.checksum, checksum)
+                && encoding.trailer.as_ref().map(Arc::as_ptr) == trailer.map(Arc::as_ptr)
+                && encoding.redacted == redacted
+        }) {
+            return Rc::clone(&encoding.bytes);
+        }
+
+        let bytes: Rc<[u8]> = if redacted {
+            Rc::from(redact(self.packet, checksum.as_ref()).encode())
+        } else if let Some(trailer) = trailer {
+            let mut packet = self.packet.clone();
+            // Can't fail, as the packet's trailer was checked.
+            let _ = trailer.resign(&mut packet);
+            packet.header.checksum = packet.calculate_checksum_with(checksum.as_ref());
+            Rc::from(packet.encode())
+        } else if Arc::ptr_eq(checksum, self.checksum) || !self.is_valid() {
+            Rc::from(self.packet.encode())
+        } else {
+            let mut packet = self.packet.clone();
+            packet.header.checksum = packet.calculate_checksum_with(checksum.as_ref());
+            Rc::from(packet.encode())
+        };
+
+        self.encoded.push(Encoding {
+            checksum: Arc::clone(checksum),
+            trailer: trailer.cloned(),
+            redacted,
+            bytes: Rc::clone(&bytes),
+        });
+        bytes
+    }
+
+    fn is_valid(&mut self) -> bool {
+        *self
+            .valid
+            .get_or_insert_with(|| self.packet.check_checksum_with(self.checksum.as_ref()).is_ok())
+    }
+}
+
+/// Copy of the packet with its data zeroed, so untrusted destinations see a
+/// sensitive packet was sent but not what it held. Its checksum is calculated
+/// with `checksum`.
+fn redact(packet: &Packet, checksum: &dyn Checksum) -> Packet {
+    let mut redacted = Packet {
+        header: packet.header,
+        data: vec![0; packet.data.len()],
+    };
+    redacted.header.checksum = redacted.calculate_checksum_with(checksum);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::process;
+
+    use clap::Parser;
+    use ctmp::OPTION_CONTROL;
+
+    use super::*;
+
+    /// A proxy with its Unix domain socket listeners in a directory of its own.
+    struct TestProxy {
+        proxy: Proxy,
+        events: Events,
+        dir: PathBuf,
+    }
+
+    impl TestProxy {
+        /// Starts a proxy with `config`, where `DIR` stands for the directory.
+        fn start(name: &str, config: &str) -> TestProxy {
+            let dir = env::temp_dir().join(format!("ctmp_proxy_{}_{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+
+            let config: Config = toml::from_str(&config.replace("DIR", dir.to_str().unwrap())).unwrap();
+            let proxy = Proxy::new(Args::parse_from(["ctmp_proxy"]), config).unwrap();
+
+            TestProxy {
+                proxy,
+                events: Events::with_capacity(EVENTS_CAPACITY),
+                dir,
+            }
+        }
+
+        fn connect(&mut self, socket: &str) -> UnixStream {
+            let stream = UnixStream::connect(self.dir.join(socket)).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            self.settle();
+            stream
+        }
+
+        /// Handles events until the proxy has been idle for a while.
+        fn settle(&mut self) {
+            for _ in 0..5 {
+                self.proxy.turn(&mut self.events, Some(Duration::from_millis(10))).unwrap();
+            }
+        }
+    }
+
+    impl Drop for TestProxy {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn send(stream: &mut UnixStream, packets: &[Packet]) {
+        let bytes: Vec<u8> = packets.iter().flat_map(Packet::encode).collect();
+        stream.write_all(&bytes).unwrap();
+    }
+
+    /// Data of every packet received until the stream has been idle for a while.
+    fn receive(stream: &mut UnixStream) -> Vec<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        let mut buffer = [0; 1024];
+        while let Ok(len @ 1..) = stream.read(&mut buffer) {
+            decoder.feed(&buffer[..len]);
+        }
+
+        let mut packets = Vec::new();
+        while let Some(packet) = decoder.decode().unwrap() {
+            packets.push(packet.data);
+        }
+        packets
+    }
+
+    fn packet(options: u8, data: &[u8]) -> Packet {
+        Packet::new(options, data.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn promoted_source_forwards_packets_sent_with_authentication() {
+        let mut proxy = TestProxy::start(
+            "promoted_source",
+            r#"
+            [source]
+            policy = "standby"
+            [[source.listen]]
+            address = "unix:DIR/source.sock"
+            auth = { token = "secret" }
+            [[destination.listen]]
+            address = "unix:DIR/destination.sock"
+            "#,
+        );
+        let mut destination = proxy.connect("destination.sock");
+
+        let mut active = proxy.connect("source.sock");
+        send(&mut active, &[packet(OPTION_CONTROL, b"secret")]);
+        proxy.settle();
+
+        let mut standby = proxy.connect("source.sock");
+        send(&mut standby, &[packet(OPTION_CONTROL, b"secret"), packet(0, b"one"), packet(0, b"two")]);
+        proxy.settle();
+        assert!(receive(&mut destination).is_empty());
+
+        drop(active);
+        proxy.settle();
+        assert_eq!(receive(&mut destination), [b"one", b"two"]);
+    }
+
+    #[test]
+    fn standby_source_that_hung_up_is_not_promoted() {
+        let mut proxy = TestProxy::start(
+            "hung_up_standby",
+            r#"
+            [source]
+            policy = "standby"
+            [[source.listen]]
+            address = "unix:DIR/source.sock"
+            [[destination.listen]]
+            address = "unix:DIR/destination.sock"
+            "#,
+        );
+        let mut destination = proxy.connect("destination.sock");
+        let active = proxy.connect("source.sock");
+
+        let mut dead = proxy.connect("source.sock");
+        send(&mut dead, &[packet(0, b"stale")]);
+        drop(dead);
+
+        let mut live = proxy.connect("source.sock");
+        send(&mut live, &[packet(0, b"live")]);
+        proxy.settle();
+
+        drop(active);
+        proxy.settle();
+        assert_eq!(receive(&mut destination), [b"live"]);
+    }
+
+    #[test]
+    fn source_that_hung_up_while_authenticating_is_not_held_in_standby() {
+        let mut proxy = TestProxy::start(
+            "hung_up_authenticating",
+            r#"
+            [source]
+            policy = "standby"
+            [[source.listen]]
+            address = "unix:DIR/source.sock"
+            auth = { token = "secret" }
+            [[destination.listen]]
+            address = "unix:DIR/destination.sock"
+            "#,
+        );
+        let mut destination = proxy.connect("destination.sock");
+
+        let mut active = proxy.connect("source.sock");
+        send(&mut active, &[packet(OPTION_CONTROL, b"secret")]);
+        proxy.settle();
+
+        let mut dead = proxy.connect("source.sock");
+        send(&mut dead, &[packet(OPTION_CONTROL, b"secret"), packet(0, b"stale")]);
+        drop(dead);
+        proxy.settle();
+        assert!(proxy.proxy.standby_sources.is_empty());
+
+        drop(active);
+        proxy.settle();
+        assert!(receive(&mut destination).is_empty());
+    }
+
+    #[test]
+    fn packet_cut_off_by_disconnection_is_quarantined() {
+        let mut proxy = TestProxy::start(
+            "cut_off",
+            r#"
+            quarantine = "DIR/quarantine.log"
+            [[source.listen]]
+            address = "unix:DIR/source.sock"
+            "#,
+        );
+        let mut source = proxy.connect("source.sock");
+
+        let bytes = packet(0, b"cut off").encode();
+        source.write_all(&bytes[..10]).unwrap();
+        drop(source);
+        proxy.settle();
+
+        let quarantined = fs::read_to_string(proxy.dir.join("quarantine.log")).unwrap();
+        let hex: String = bytes[..10].iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert!(quarantined.ends_with(&format!("\t{}\t{}\n", CtmpError::Incomplete, hex)));
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+
+use ctmp::CtmpError;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::listener::Role;
+use crate::stream::Endpoint;
+
+/// A file that bytes rejected by a decoder are appended to, so bad packets can
+/// be inspected after the fact.
+///
+/// Each rejected frame is one line of tab separated fields: the UTC time in
+/// RFC 3339 format, the client's role and address, the reason it was rejected
+/// and the raw bytes in hex.
+pub struct Quarantine {
+    pub path: PathBuf,
+    file: File,
+}
+
+impl Quarantine {
+    /// Opens the file for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Quarantine, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::other(format!("Failed to open quarantine file {}: {}", path.display(), e)))?;
+
+        Ok(Quarantine {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Appends the `bytes` rejected from the client at `address` because of `e`.
+    pub fn write(&mut self, role: Role, address: &Endpoint, e: &CtmpError, bytes: &[u8]) -> Result<(), Error> {
+        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).map_err(Error::other)?;
+
+        let mut line = format!("{}\t{} {}\t{}\t", timestamp, role, address, e);
+        line.reserve(bytes.len() * 2 + 1);
+        for byte in bytes {
+            let _ = write!(line, "{:02x}", byte);
+        }
+        line.push('\n');
+
+        // A single write per line, so lines aren't interleaved with other writers.
+        self.file.write_all(line.as_bytes())
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ctmp::{Checksum, CtmpError, Decoder, Packet};
+
+use crate::auth::Handshake;
+use crate::stream::{Endpoint, Stream};
+
+/// What happens when a source connects while another source is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourcePolicy {
+    /// Read every connected source.
+    #[default]
+    Concurrent,
+    /// Close the new source.
+    Reject,
+    /// Close the connected source and read the new one instead.
+    Preempt,
+    /// Hold the new source unread until the connected source disconnects.
+    Standby,
+}
+
+impl FromStr for SourcePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SourcePolicy, Error> {
+        match s {
+            "concurrent" => Ok(SourcePolicy::Concurrent),
+            "reject" => Ok(SourcePolicy::Reject),
+            "preempt" => Ok(SourcePolicy::Preempt),
+            "standby" => Ok(SourcePolicy::Standby),
+            _ => Err(Error::other(format!("Unknown source policy: {}", s))),
+        }
+    }
+}
+
+/// A connected source client and the framing state of its stream.
+pub struct Source {
+    pub stream: Stream,
+    pub address: Endpoint,
+    /// Address of the listener that accepted the source.
+    pub listener: Endpoint,
+    decoder: Decoder,
+    /// Whether the source is waiting in the proxy's ready queue.
+    pub queued: bool,
+    /// Whether the source is held unread as a failover for the active source.
+    pub standby: bool,
+    /// Set until the source authenticates. Its packets aren't forwarded, and
+    /// the source policy isn't applied to it, until then.
+    pub handshake: Option<Handshake>,
+    /// Whether the source's listener has `auth` set, which reserves control
+    /// packets for authentication. Other listeners forward them like any
+    /// other packet.
+    pub reserves_control: bool,
+    /// Whether an event reported the source shut down its end of the
+    /// connection or failed. Standby sources aren't read, so this is how
+    /// they're found to have disconnected.
+    pub hung_up: bool,
+    /// Whether the source is registered for writable events, which it only
+    /// needs while TLS records are waiting to be sent.
+    pub writable: bool,
+    /// Number of packets with unknown option bits or non-zero padding
+    /// forwarded with lenient header validation.
+    pub nonconforming: u64,
+}
+
+impl Source {
+    pub fn new(
+        stream: Stream,
+        address: Endpoint,
+        listener: Endpoint,
+        decoder: Decoder,
+        handshake: Option<Handshake>,
+    ) -> Source {
+        Source {
+            stream,
+            address,
+            listener,
+            decoder,
+            queued: false,
+            standby: false,
+            reserves_control: handshake.is_some(),
+            handshake,
+            hung_up: false,
+            writable: false,
+            nonconforming: 0,
+        }
+    }
+
+    /// Checks whether the source is authenticated and not in standby.
+    pub fn is_active(&self) -> bool {
+        !self.standby && self.handshake.is_none()
+    }
+
+    /// Performs a single read from the source into its decoder.
+    ///
+    /// Returns the number of bytes read, or `CtmpError::PeerClosed` if the
+    /// source disconnected.
+    pub fn read(&mut self) -> Result<usize, CtmpError> {
+        loop {
+            match self.decoder.read_from(&mut self.stream) {
+                Err(CtmpError::Io(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut Decoder {
+        &mut self.decoder
+    }
+
+    /// Takes the next complete packet read from the source, counting it if
+    /// it's nonconforming.
+    pub fn decode(&mut self) -> Result<Option<Packet>, CtmpError> {
+        let result = self.decoder.decode();
+        if self.decoder.nonconforming().is_some() {
+            self.nonconforming += 1;
+        }
+        result
+    }
+
+    /// Algorithm the checksums of the source's packets are calculated with.
+    pub fn checksum(&self) -> &Arc<dyn Checksum> {
+        self.decoder.checksum()
+    }
+
+    /// Checks whether 'sensitive' packets from the source must carry a valid
+    /// HMAC trailer.
+    pub fn checks_trailers(&self) -> bool {
+        self.decoder.trailer().is_some()
+    }
+
+    /// Bytes discarded by the last `decode` that returned an error.
+    pub fn rejected(&self) -> &[u8] {
+        self.decoder.rejected()
+    }
+
+    /// Why the header of the last decoded packet doesn't follow the spec, if
+    /// it doesn't and lenient header validation let it through.
+    pub fn nonconformity(&self) -> Option<&CtmpError> {
+        self.decoder.nonconforming()
+    }
+
+    /// Original checksum of the last decoded packet, if it was repaired.
+    pub fn repaired(&self) -> Option<u16> {
+        self.decoder.repaired()
+    }
+}
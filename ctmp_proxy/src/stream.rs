@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io::{Error, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use mio::event;
+use mio::net::{TcpStream, UnixStream};
+use mio::{Interest, Registry, Token};
+
+use crate::tls::{Identity, TlsStream};
+
+/// Prefix marking an endpoint as a Unix domain socket path.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where a listener binds to, or where a client connected from.
+///
+/// Written as `127.0.0.1:33333`, `[::1]:33333` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{}", address),
+            Endpoint::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Endpoint, Error> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                return Err(Error::other(format!("Missing socket path: {}", s)));
+            }
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+
+        s.parse()
+            .map(Endpoint::Tcp)
+            .map_err(|e| Error::other(format!("Invalid address {}: {}", s, e)))
+    }
+}
+
+/// A connection to a client, over TCP or a Unix domain socket, optionally
+/// encrypted with TLS.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    /// Checks whether TLS records are waiting for the socket to be writable.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Tls(stream) => stream.wants_write(),
+            Stream::Tcp(_) | Stream::Unix(_) => false,
+        }
+    }
+
+    /// Checks whether the TLS handshake is still in progress.
+    pub fn is_handshaking(&self) -> bool {
+        match self {
+            Stream::Tls(stream) => stream.is_handshaking(),
+            Stream::Tcp(_) | Stream::Unix(_) => false,
+        }
+    }
+
+    /// Identity from the client's TLS certificate, once it's been verified.
+    pub fn peer_identity(&self) -> Option<Identity> {
+        match self {
+            Stream::Tls(stream) => stream.peer_identity(),
+            Stream::Tcp(_) | Stream::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buffer),
+            Stream::Unix(stream) => stream.read(buffer),
+            Stream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buffer),
+            Stream::Unix(stream) => stream.write(buffer),
+            Stream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl event::Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            Stream::Unix(stream) => stream.register(registry, token, interests),
+            Stream::Tls(stream) => stream.socket.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            Stream::Unix(stream) => stream.reregister(registry, token, interests),
+            Stream::Tls(stream) => stream.socket.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            Stream::Unix(stream) => stream.deregister(registry),
+            Stream::Tls(stream) => stream.socket.deregister(registry),
+        }
+    }
+}
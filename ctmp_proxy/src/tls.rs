@@ -0,0 +1,228 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use crate::config::TlsConfig;
+use crate::stream::Stream;
+
+/// Names a client proved it owns by presenting a certificate signed by a
+/// trusted CA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Common name (CN) of the certificate's subject.
+    pub common_name: Option<String>,
+    /// DNS names, email addresses and URIs in the certificate's subject
+    /// alternative name (SAN) extension.
+    pub alt_names: Vec<String>,
+}
+
+impl Identity {
+    /// Parses the identity out of a DER encoded certificate.
+    fn from_certificate(cert: &CertificateDer) -> Option<Identity> {
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .find_map(|name| name.as_str().ok())
+            .map(String::from);
+
+        let alt_names = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                        Some(String::from(*name))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(Identity { common_name, alt_names })
+    }
+
+    /// Checks whether `name` is the common name or one of the alternative names.
+    pub fn matches(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name) || self.alt_names.iter().any(|alt_name| alt_name == name)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.common_name {
+            Some(common_name) => write!(f, "CN={}", common_name)?,
+            None => write!(f, "no CN")?,
+        }
+
+        if !self.alt_names.is_empty() {
+            write!(f, " (SAN: {})", self.alt_names.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Loads the certificate chain and private key in `config`, and the CAs that
+/// client certificates must be signed by, if any.
+///
+/// Returns error if a file can't be read or the certificate and key don't match.
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let certs = read_certs(&config.cert)?;
+
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| Error::other(format!("Failed to read private key {}: {}", config.key.display(), e)))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots.add(cert).map_err(Error::other)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(certs, key).map_err(Error::other)?;
+
+    Ok(Arc::new(server_config))
+}
+
+/// Reads every certificate in the PEM file at `path`.
+///
+/// Returns error if the file can't be read or has no certificates.
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::other(format!("Failed to read certificate {}: {}", path.display(), e)))?;
+
+    if certs.is_empty() {
+        return Err(Error::other(format!("No certificate in {}", path.display())));
+    }
+
+    Ok(certs)
+}
+
+/// A TLS connection to a client over a non-blocking socket.
+///
+/// Reads and writes never block. Encrypted records that the socket can't take
+/// yet are buffered until the next read, write or flush.
+pub struct TlsStream {
+    connection: ServerConnection,
+    pub socket: Stream,
+}
+
+impl TlsStream {
+    pub fn new(socket: Stream, config: Arc<ServerConfig>) -> Result<TlsStream, Error> {
+        let connection = ServerConnection::new(config).map_err(Error::other)?;
+        Ok(TlsStream { connection, socket })
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.connection.is_handshaking()
+    }
+
+    /// Identity from the client's certificate, once the handshake is complete.
+    /// None if the listener doesn't ask for client certificates, or the
+    /// certificate can't be parsed.
+    pub fn peer_identity(&self) -> Option<Identity> {
+        if self.connection.is_handshaking() {
+            return None;
+        }
+
+        let cert = self.connection.peer_certificates()?.first()?;
+        Identity::from_certificate(cert)
+    }
+
+    /// Checks whether there are records waiting for the socket to take them.
+    pub fn wants_write(&self) -> bool {
+        self.connection.wants_write()
+    }
+
+    /// Writes buffered records to the socket until it would block.
+    fn write_records(&mut self) -> Result<(), Error> {
+        while self.connection.wants_write() {
+            match self.connection.write_tls(&mut self.socket) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `write_records`, but succeeds if the socket would block.
+    fn try_write_records(&mut self) -> Result<(), Error> {
+        match self.write_records() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        // Sends records left over from the last read, e.g. when this read is
+        // for a writable event and the socket has nothing to decrypt.
+        self.try_write_records()?;
+
+        loop {
+            match self.connection.reader().read(buffer) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // No plaintext left, so decrypt more records from the socket.
+            if self.connection.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+
+            if let Err(e) = self.connection.process_new_packets() {
+                // Tell the client why before giving up on it.
+                let _ = self.try_write_records();
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            }
+
+            // Handshake messages may need answering.
+            self.try_write_records()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        self.try_write_records()?;
+
+        // The connection's buffers are full until the socket takes more records.
+        let bytes_written = self.connection.writer().write(buffer)?;
+        if bytes_written == 0 && !buffer.is_empty() {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+
+        self.try_write_records()?;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.write_records()
+    }
+}